@@ -0,0 +1,146 @@
+//! Static table of the options Kanata accepts in `defcfg`.
+
+use crate::SymbolKind;
+
+/// What an option's value may be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    /// `yes` or `no` (`true`/`false` are accepted too)
+    Bool,
+    /// A non-negative whole number
+    Number,
+    /// One of a fixed set of words
+    OneOf(&'static [&'static str]),
+    /// The bare name of a symbol defined elsewhere in the config
+    Name(SymbolKind),
+    /// Anything, e.g. paths, device names or lists; not checked
+    Any,
+}
+
+impl ValueKind {
+    /// Values to offer as completions.
+    pub fn suggestions(self) -> &'static [&'static str] {
+        match self {
+            ValueKind::Bool => &["yes", "no"],
+            ValueKind::OneOf(values) => values,
+            ValueKind::Number | ValueKind::Name(_) | ValueKind::Any => &[],
+        }
+    }
+
+    /// Whether a single-atom value is acceptable.
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            ValueKind::Bool => matches!(value, "yes" | "no" | "true" | "false"),
+            ValueKind::Number => value.parse::<u64>().is_ok(),
+            ValueKind::OneOf(values) => values.contains(&value),
+            // Whether the name is defined is checked against the document's symbols
+            ValueKind::Name(_) | ValueKind::Any => true,
+        }
+    }
+
+    /// Short description of the accepted values, for messages.
+    pub fn describe(self) -> String {
+        match self {
+            ValueKind::Bool => "`yes` or `no`".to_string(),
+            ValueKind::Number => "a number".to_string(),
+            ValueKind::OneOf(values) => {
+                let values: Vec<String> = values.iter().map(|value| format!("`{}`", value)).collect();
+                format!("one of {}", values.join(", "))
+            }
+            ValueKind::Name(kind) => format!("{} name", kind.label()),
+            ValueKind::Any => "any value".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DefcfgOption {
+    pub name: &'static str,
+    pub value: ValueKind,
+}
+
+const fn option(name: &'static str, value: ValueKind) -> DefcfgOption {
+    DefcfgOption { name, value }
+}
+
+const BOOL: ValueKind = ValueKind::Bool;
+const NUMBER: ValueKind = ValueKind::Number;
+const ANY: ValueKind = ValueKind::Any;
+
+pub const DEFCFG_OPTIONS: &[DefcfgOption] = &[
+    // General behaviour
+    option("process-unmapped-keys", BOOL),
+    option("block-unmapped-keys", BOOL),
+    option("danger-enable-cmd", BOOL),
+    option("log-layer-changes", BOOL),
+    option("delegate-to-first-layer", BOOL),
+    option("concurrent-tap-hold", BOOL),
+    option("allow-hardware-repeat", BOOL),
+    option("override-release-on-activation", BOOL),
+    option("transparent-key-resolution", ANY),
+    option("alias-to-trigger-on-load", ValueKind::Name(SymbolKind::Alias)),
+    option("rapid-event-delay", NUMBER),
+    option("dynamic-macro-max-presses", NUMBER),
+    option("dynamic-macro-record-stop-truncation", NUMBER),
+    option("chords-v2-min-idle", NUMBER),
+    // Sequences
+    option("sequence-timeout", NUMBER),
+    option(
+        "sequence-input-mode",
+        ValueKind::OneOf(&["visible-backspaced", "hidden-suppressed", "hidden-delay-type"]),
+    ),
+    option("sequence-backtrack-modcancel", BOOL),
+    option("sequence-always-on", BOOL),
+    // Mouse
+    option("movemouse-inherit-accel-state", BOOL),
+    option("movemouse-smooth-diagonals", BOOL),
+    option("mouse-movement-key", ANY),
+    // Linux
+    option("linux-dev", ANY),
+    option("linux-dev-names-include", ANY),
+    option("linux-dev-names-exclude", ANY),
+    option("linux-continue-if-no-devs-found", BOOL),
+    option("linux-unicode-u-code", ANY),
+    option(
+        "linux-unicode-termination",
+        ValueKind::OneOf(&["enter", "space", "enter-space", "space-enter"]),
+    ),
+    option("linux-x11-repeat-delay-rate", ANY),
+    option("linux-use-trackpoint-property", BOOL),
+    option("linux-output-device-name", ANY),
+    option("linux-output-device-bus-type", ANY),
+    option(
+        "linux-device-detect-mode",
+        ValueKind::OneOf(&["any", "keyboard-only", "keyboard-mice"]),
+    ),
+    // macOS
+    option("macos-dev-names-include", ANY),
+    option("macos-dev-names-exclude", ANY),
+    // Windows
+    option(
+        "windows-altgr",
+        ValueKind::OneOf(&["cancel-lctl-press", "add-lctl-release"]),
+    ),
+    option("windows-interception-mouse-hwid", ANY),
+    option("windows-interception-mouse-hwids", ANY),
+    option("windows-interception-keyboard-hwids", ANY),
+    option("windows-interception-keyboard-hwids-exclude", ANY),
+    option("windows-interception-mouse-hwids-exclude", ANY),
+    option("windows-sync-keystates", BOOL),
+    // Tray icon and notifications
+    option("tray-icon", ANY),
+    option("icon-match-layer-name", BOOL),
+    option("tooltip-layer-changes", BOOL),
+    option("tooltip-show-blank", BOOL),
+    option("tooltip-no-base", BOOL),
+    option("tooltip-duration", NUMBER),
+    option("tooltip-size", ANY),
+    option("notify-cfg-reload", BOOL),
+    option("notify-cfg-reload-silent", BOOL),
+    option("notify-error", BOOL),
+];
+
+/// Look up a defcfg option by name.
+pub fn lookup(name: &str) -> Option<&'static DefcfgOption> {
+    DEFCFG_OPTIONS.iter().find(|option| option.name == name)
+}
@@ -0,0 +1,197 @@
+//! Static table of the keycode names Kanata recognizes in `defsrc`.
+
+#[derive(Debug)]
+pub struct Keycode {
+    /// Canonical name, as used in Kanata's own examples
+    pub name: &'static str,
+    /// Short human-readable description of the key, shown on hover
+    pub description: &'static str,
+    /// Alternative spellings accepted by the parser
+    pub aliases: &'static [&'static str],
+}
+
+const fn key(
+    name: &'static str,
+    description: &'static str,
+    aliases: &'static [&'static str],
+) -> Keycode {
+    Keycode {
+        name,
+        description,
+        aliases,
+    }
+}
+
+pub const KEYCODES: &[Keycode] = &[
+    // Function row
+    key("esc", "Escape", &[]),
+    key("f1", "F1 function key", &[]),
+    key("f2", "F2 function key", &[]),
+    key("f3", "F3 function key", &[]),
+    key("f4", "F4 function key", &[]),
+    key("f5", "F5 function key", &[]),
+    key("f6", "F6 function key", &[]),
+    key("f7", "F7 function key", &[]),
+    key("f8", "F8 function key", &[]),
+    key("f9", "F9 function key", &[]),
+    key("f10", "F10 function key", &[]),
+    key("f11", "F11 function key", &[]),
+    key("f12", "F12 function key", &[]),
+    key("f13", "F13 function key", &[]),
+    key("f14", "F14 function key", &[]),
+    key("f15", "F15 function key", &[]),
+    key("f16", "F16 function key", &[]),
+    key("f17", "F17 function key", &[]),
+    key("f18", "F18 function key", &[]),
+    key("f19", "F19 function key", &[]),
+    key("f20", "F20 function key", &[]),
+    key("f21", "F21 function key", &[]),
+    key("f22", "F22 function key", &[]),
+    key("f23", "F23 function key", &[]),
+    key("f24", "F24 function key", &[]),
+    // Number row
+    key("grv", "Grave accent / backtick", &["`", "grave"]),
+    key("1", "Number row 1", &[]),
+    key("2", "Number row 2", &[]),
+    key("3", "Number row 3", &[]),
+    key("4", "Number row 4", &[]),
+    key("5", "Number row 5", &[]),
+    key("6", "Number row 6", &[]),
+    key("7", "Number row 7", &[]),
+    key("8", "Number row 8", &[]),
+    key("9", "Number row 9", &[]),
+    key("0", "Number row 0", &[]),
+    key("min", "Minus", &["-", "minus"]),
+    key("eql", "Equals", &["=", "equal"]),
+    key("bspc", "Backspace", &["bks", "backspace"]),
+    // Letter rows
+    key("tab", "Tab", &[]),
+    key("q", "Letter Q", &[]),
+    key("w", "Letter W", &[]),
+    key("e", "Letter E", &[]),
+    key("r", "Letter R", &[]),
+    key("t", "Letter T", &[]),
+    key("y", "Letter Y", &[]),
+    key("u", "Letter U", &[]),
+    key("i", "Letter I", &[]),
+    key("o", "Letter O", &[]),
+    key("p", "Letter P", &[]),
+    key("lbrc", "Left square bracket", &["["]),
+    key("rbrc", "Right square bracket", &["]"]),
+    key("bksl", "Backslash", &["\\", "yen"]),
+    key("caps", "Caps Lock", &["capslock"]),
+    key("a", "Letter A", &[]),
+    key("s", "Letter S", &[]),
+    key("d", "Letter D", &[]),
+    key("f", "Letter F", &[]),
+    key("g", "Letter G", &[]),
+    key("h", "Letter H", &[]),
+    key("j", "Letter J", &[]),
+    key("k", "Letter K", &[]),
+    key("l", "Letter L", &[]),
+    key("scln", "Semicolon", &[";", "semicolon"]),
+    key("apo", "Apostrophe / single quote", &["'", "apos", "quote"]),
+    key("ret", "Return / Enter", &["return", "ent", "enter"]),
+    key("lsft", "Left Shift", &["lshift", "lshft"]),
+    key("z", "Letter Z", &[]),
+    key("x", "Letter X", &[]),
+    key("c", "Letter C", &[]),
+    key("v", "Letter V", &[]),
+    key("b", "Letter B", &[]),
+    key("n", "Letter N", &[]),
+    key("m", "Letter M", &[]),
+    key("comm", "Comma", &[",", "comma"]),
+    key(".", "Period / dot", &["dot", "period"]),
+    key("/", "Forward slash", &["slsh", "slash"]),
+    key("rsft", "Right Shift", &["rshift", "rshft"]),
+    key("nubs", "Non-US backslash (ISO key next to left Shift)", &["lsgt", "102d"]),
+    // Bottom row
+    key("lctl", "Left Control", &["lctrl", "lcontrol"]),
+    key("lmet", "Left Meta / Super / Windows / Command key", &["lmeta", "lsuper", "lgui", "lwin", "lcmd"]),
+    key("lalt", "Left Alt / Option", &[]),
+    key("spc", "Space", &["space"]),
+    key("ralt", "Right Alt / AltGr / Option", &[]),
+    key("rmet", "Right Meta / Super / Windows / Command key", &["rmeta", "rsuper", "rgui", "rwin", "rcmd"]),
+    key("rctl", "Right Control", &["rctrl", "rcontrol"]),
+    key("menu", "Menu / Compose / Application key", &["comp", "compose", "apps"]),
+    // Navigation cluster
+    key("prnt", "Print Screen / SysRq", &["print", "prtsc", "sys"]),
+    key("slck", "Scroll Lock", &["scrlck", "scrolllock"]),
+    key("pause", "Pause / Break", &["brk", "break"]),
+    key("ins", "Insert", &["insert"]),
+    key("home", "Home", &[]),
+    key("pgup", "Page Up", &["pageup"]),
+    key("del", "Delete (forward)", &["delete"]),
+    key("end", "End", &[]),
+    key("pgdn", "Page Down", &["pagedown"]),
+    key("up", "Up arrow", &[]),
+    key("lft", "Left arrow", &["left"]),
+    key("down", "Down arrow", &[]),
+    key("rght", "Right arrow", &["right"]),
+    // Numpad
+    key("nlck", "Num Lock", &["numlock"]),
+    key("kp/", "Keypad slash", &["kpslash"]),
+    key("kp*", "Keypad asterisk", &["kpasterisk"]),
+    key("kp-", "Keypad minus", &["kpminus"]),
+    key("kp+", "Keypad plus", &["kpplus"]),
+    key("kprt", "Keypad Enter", &["kpenter"]),
+    key("kp.", "Keypad dot", &["kpdot"]),
+    key("kp=", "Keypad equals", &["kpequal"]),
+    key("kp0", "Keypad 0", &[]),
+    key("kp1", "Keypad 1", &[]),
+    key("kp2", "Keypad 2", &[]),
+    key("kp3", "Keypad 3", &[]),
+    key("kp4", "Keypad 4", &[]),
+    key("kp5", "Keypad 5", &[]),
+    key("kp6", "Keypad 6", &[]),
+    key("kp7", "Keypad 7", &[]),
+    key("kp8", "Keypad 8", &[]),
+    key("kp9", "Keypad 9", &[]),
+    // Media and system
+    key("mute", "Mute volume", &[]),
+    key("volu", "Volume up", &["volumeup"]),
+    key("vold", "Volume down", &["volumedown"]),
+    key("brup", "Brightness up", &["brightnessup"]),
+    key("brdown", "Brightness down", &["brdwn", "brightnessdown"]),
+    key("prev", "Previous track", &["previoussong"]),
+    key("next", "Next track", &["nextsong"]),
+    key("pp", "Play / Pause", &["playpause"]),
+    key("stop", "Stop media", &["stopcd"]),
+    key("eject", "Eject", &["ejectcd"]),
+    key("sleep", "Sleep", &[]),
+    key("power", "Power", &[]),
+    key("fn", "Function (Fn) key", &[]),
+    // International keys
+    key("ro", "Japanese Ro key", &[]),
+    key("kana", "Japanese Katakana key", &["katakana"]),
+    key("hiragana", "Japanese Hiragana key", &[]),
+    key("henk", "Japanese Henkan (convert) key", &["henkan"]),
+    key("mhnk", "Japanese Muhenkan (no-convert) key", &["muhenkan"]),
+    key("hngl", "Korean Hangeul key", &["hangeul"]),
+    key("hnja", "Korean Hanja key", &["hanja"]),
+    // Mouse buttons and wheel
+    key("mlft", "Left mouse button", &["mouseleft"]),
+    key("mrgt", "Right mouse button", &["mouseright"]),
+    key("mmid", "Middle mouse button", &["mousemid"]),
+    key("mbck", "Mouse back button", &["mousebackward"]),
+    key("mfwd", "Mouse forward button", &["mouseforward"]),
+    key("mwu", "Mouse wheel up", &["mousewheelup"]),
+    key("mwd", "Mouse wheel down", &["mousewheeldown"]),
+    key("mwl", "Mouse wheel left", &["mousewheelleft"]),
+    key("mwr", "Mouse wheel right", &["mousewheelright"]),
+];
+
+/// Canonical name for a keycode alias, or `None` if `name` is canonical or unknown.
+pub fn canonical_name(name: &str) -> Option<&'static str> {
+    KEYCODES
+        .iter()
+        .find(|keycode| keycode.aliases.contains(&name))
+        .map(|keycode| keycode.name)
+}
+
+/// Look up a keycode by its canonical name or any of its aliases.
+pub fn lookup(name: &str) -> Option<&'static Keycode> {
+    KEYCODES
+        .iter()
+        .find(|keycode| keycode.name == name || keycode.aliases.contains(&name))
+}
@@ -1,55 +1,653 @@
-use tower_lsp::jsonrpc::Result;
+use tower_lsp::jsonrpc::{Error, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::RwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use unicode_segmentation::UnicodeSegmentation;
 
+mod defcfg;
+mod keycodes;
+
+use defcfg::DEFCFG_OPTIONS;
+use keycodes::{KEYCODES, canonical_name};
+
+/// Actions whose first argument is a layer name
+const LAYER_ACTIONS: &[&str] = &["layer-switch", "layer-toggle", "layer-while-held"];
+
+/// What completion offers at the cursor
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompletionContext {
+    Keycode,
+    Layer,
+    /// The head of a nested list, where an action name goes
+    Action,
+    /// A binding or action argument, where an `@alias` can go
+    Alias,
+    /// An option name in defcfg
+    DefcfgKey,
+    /// An option value in defcfg
+    DefcfgValue,
+}
+
+/// Multi-argument actions offered as snippets: the action name and its arguments as tab stops
+const ACTION_SNIPPETS: &[(&str, &str)] = &[
+    ("tap-hold", "${1:200} ${2:200} ${3:tap} ${4:hold}"),
+    ("tap-hold-press", "${1:200} ${2:200} ${3:tap} ${4:hold}"),
+    ("tap-hold-release", "${1:200} ${2:200} ${3:tap} ${4:hold}"),
+    ("tap-dance", "${1:200} (${2:actions})"),
+    ("one-shot", "${1:500} ${2:key}"),
+    ("multi", "${1:key} ${2:key}"),
+    ("macro", "${1:keys}"),
+    ("fork", "${1:left} ${2:right} (${3:right-trigger-keys})"),
+    ("caps-word", "${1:2000}"),
+    ("layer-switch", "${1:layer}"),
+    ("layer-toggle", "${1:layer}"),
+    ("layer-while-held", "${1:layer}"),
+    ("unicode", "${1:char}"),
+];
+
+/// Where each kind of completion applies: the form keyword, the argument index
+/// (`None` for any argument), whether the cursor sits in a list at that argument
+/// rather than directly in the form, and what to offer there.
+///
+/// A list argument holds items rather than an action, so its first word is one of
+/// those items and not an action name.
+const COMPLETION_CONTEXTS: &[(&str, Option<usize>, bool, CompletionContext)] = &[
+    ("defsrc", None, false, CompletionContext::Keycode),
+    ("layer-switch", Some(1), false, CompletionContext::Layer),
+    ("layer-toggle", Some(1), false, CompletionContext::Layer),
+    ("layer-while-held", Some(1), false, CompletionContext::Layer),
+    ("deflayermap", Some(1), true, CompletionContext::Layer),
+    ("tap-dance", Some(2), true, CompletionContext::Alias),
+    ("tap-dance-eager", Some(2), true, CompletionContext::Alias),
+    ("fork", Some(3), true, CompletionContext::Keycode),
+    ("tap-hold-release-keys", Some(5), true, CompletionContext::Keycode),
+    ("tap-hold-except-keys", Some(5), true, CompletionContext::Keycode),
+];
+
+/// Actions whose first argument is a virtual key, which is also what a defseq name triggers.
+/// Both the older fake key spellings and the newer virtual key ones are accepted.
+const VIRTUAL_KEY_ACTIONS: &[&str] = &[
+    "on-press-fakekey",
+    "on-release-fakekey",
+    "on-idle-fakekey",
+    "on-press-virtualkey",
+    "on-release-virtualkey",
+    "on-idle-virtualkey",
+    "press-virtualkey",
+    "release-virtualkey",
+    "tap-virtualkey",
+    "toggle-virtualkey",
+];
+
+/// Forms declaring virtual keys, under their old and new names
+const VIRTUAL_KEY_FORMS: &[&str] = &["deffakekeys", "defvirtualkeys"];
+
+/// Forms whose first argument is a template name
+const TEMPLATE_EXPANSIONS: &[&str] = &["template-expand", "t!"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SymbolKind {
+    Alias,
+    Layer,
+    Sequence,
+    VirtualKey,
+    Template,
+}
+
+impl SymbolKind {
+    fn label(self) -> &'static str {
+        match self {
+            SymbolKind::Alias => "alias",
+            SymbolKind::Layer => "layer",
+            SymbolKind::Sequence => "sequence",
+            SymbolKind::VirtualKey => "virtual key",
+            SymbolKind::Template => "template",
+        }
+    }
+    
+    /// Forms that reference a symbol of this kind by their first argument
+    fn reference_forms(self) -> &'static [&'static str] {
+        match self {
+            SymbolKind::Alias => &[],
+            SymbolKind::Layer => LAYER_ACTIONS,
+            SymbolKind::Sequence | SymbolKind::VirtualKey => VIRTUAL_KEY_ACTIONS,
+            SymbolKind::Template => TEMPLATE_EXPANSIONS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Atom,
+}
+
+/// A token of comment-masked text, as a byte range into that text
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
 #[derive(Debug, Clone)]
 struct Definition {
     uri: Url,
+    /// Range of the symbol name
     range: Range,
+    /// Range of the opening keyword of the defining form, e.g. `(defalias`
+    keyword_range: Range,
+    /// Range of the value paired with the name in forms like `defalias`, which may span lines
+    body_range: Option<Range>,
+    /// Range of the whole defining form, from its opening paren to its closing one
+    full_range: Range,
+}
+
+/// Parsed defsrc layout, kept until the defsrc form it came from changes
+#[derive(Debug)]
+struct DefsrcCache {
+    source: String,
+    tab_size: usize,
+    layout: Option<Vec<Vec<usize>>>,
+}
+
+/// How parsing a document's temporary copy ended
+#[derive(Debug)]
+enum ParseOutcome {
+    Valid,
+    /// The parser rejected the config, with its error report
+    Invalid(String),
+    /// The parser panicked, with the panic message
+    Panicked(String),
+}
+
+/// A layer action found by `kanata.listLayerTransitions`
+#[derive(Debug)]
+struct LayerTransition {
+    /// Where the target layer is named
+    location: Location,
+    action: String,
+    layer: String,
+    /// Whether any indexed document defines the target layer
+    defined: bool,
 }
 
 #[derive(Debug)]
 struct DocumentSymbols {
     aliases: HashMap<String, Definition>,
     layers: HashMap<String, Definition>,
+    sequences: HashMap<String, Definition>,
+    virtual_keys: HashMap<String, Definition>,
+    templates: HashMap<String, Definition>,
+    /// Parameter names of each template, for arity checks
+    template_params: HashMap<String, Vec<String>>,
+    /// First and last line of every stretch of text that can change a definition:
+    /// whole defalias, defseq, virtual key and template forms, and each deflayer
+    /// through its name
+    defining_lines: Vec<(u32, u32)>,
+    /// Aliases and layers the document refers to, so unused symbols can be found
+    /// without rereading every indexed file
+    referenced_aliases: HashSet<String>,
+    referenced_layers: HashSet<String>,
+}
+
+impl DocumentSymbols {
+    fn definitions(&self, kind: SymbolKind) -> &HashMap<String, Definition> {
+        match kind {
+            SymbolKind::Alias => &self.aliases,
+            SymbolKind::Layer => &self.layers,
+            SymbolKind::Sequence => &self.sequences,
+            SymbolKind::VirtualKey => &self.virtual_keys,
+            SymbolKind::Template => &self.templates,
+        }
+    }
+}
+
+/// How items are placed within their defsrc column
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ColumnAlign {
+    #[default]
+    Left,
+    Right,
+    Center,
+}
+
+/// How diagnostics reach the client
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum DiagnosticsMode {
+    /// Published with `textDocument/publishDiagnostics`
+    Push,
+    /// Served from the cache on `textDocument/diagnostic` requests
+    Pull,
+    #[default]
+    Both,
+}
+
+impl DiagnosticsMode {
+    fn pushes(self) -> bool {
+        self != DiagnosticsMode::Pull
+    }
+    
+    fn pulls(self) -> bool {
+        self != DiagnosticsMode::Push
+    }
+}
+
+/// When documents are validated
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum DiagnosticsTrigger {
+    /// On open and on every change
+    #[default]
+    Change,
+    /// Only when the document is saved
+    Save,
+    /// Only when the document is opened
+    Open,
+}
+
+/// Where the diagnostic codes are documented
+const DIAGNOSTICS_DOCS_URL: &str = "https://github.com/willpuckett/zed-kanata/blob/main/kanata-lsp/README.md#diagnostics";
+
+/// Stable code for each kind of diagnostic, so clients can filter or suppress them
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiagnosticCode {
+    ParseError,
+    ValidationTimeout,
+    TempFile,
+    MissingInclude,
+    UndefinedVirtualKey,
+    UndefinedTemplate,
+    TemplateArity,
+    NoncanonicalKeycode,
+    SparseLayer,
+    UnusedSymbol,
+    MissingDefsrc,
+    UnknownDefcfgOption,
+    InvalidDefcfgValue,
+    UndefinedDefcfgName,
+    TapHoldTiming,
+    ParserPanic,
+    MissingName,
+    StrayToken,
+}
+
+impl DiagnosticCode {
+    fn as_str(self) -> &'static str {
+        match self {
+            DiagnosticCode::ParseError => "kanata.parse-error",
+            DiagnosticCode::ValidationTimeout => "kanata.validation-timeout",
+            DiagnosticCode::TempFile => "kanata.temp-file",
+            DiagnosticCode::MissingInclude => "kanata.missing-include",
+            DiagnosticCode::UndefinedVirtualKey => "kanata.undefined-virtual-key",
+            DiagnosticCode::UndefinedTemplate => "kanata.undefined-template",
+            DiagnosticCode::TemplateArity => "kanata.template-arity",
+            DiagnosticCode::NoncanonicalKeycode => "kanata.noncanonical-keycode",
+            DiagnosticCode::SparseLayer => "kanata.sparse-layer",
+            DiagnosticCode::UnusedSymbol => "kanata.unused-symbol",
+            DiagnosticCode::MissingDefsrc => "kanata.missing-defsrc",
+            DiagnosticCode::UnknownDefcfgOption => "kanata.unknown-defcfg-option",
+            DiagnosticCode::InvalidDefcfgValue => "kanata.invalid-defcfg-value",
+            DiagnosticCode::UndefinedDefcfgName => "kanata.undefined-defcfg-name",
+            DiagnosticCode::TapHoldTiming => "kanata.tap-hold-timing",
+            DiagnosticCode::ParserPanic => "kanata.parser-panic",
+            DiagnosticCode::MissingName => "kanata.missing-name",
+            DiagnosticCode::StrayToken => "kanata.stray-token",
+        }
+    }
+    
+    fn code(self) -> Option<NumberOrString> {
+        Some(NumberOrString::String(self.as_str().to_string()))
+    }
+    
+    fn description(self) -> Option<CodeDescription> {
+        Url::parse(DIAGNOSTICS_DOCS_URL).ok().map(|href| CodeDescription { href })
+    }
+}
+
+/// Numbers each validation's temporary directory
+static VALIDATION_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Columns a tab spans when the editor doesn't say
+const DEFAULT_TAB_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct FormatOptions {
+    column_align: ColumnAlign,
+    /// Columns a tab spans, for lining layers up with a tab-separated defsrc
+    tab_size: usize,
+    /// Blank lines kept between consecutive top-level forms
+    blank_lines_between_forms: usize,
+    /// Collapse runs of spaces between items in forms other than defsrc and layers
+    normalize_spacing: bool,
+    /// Format layers with trailing `;;` comments on their rows, lining the comments up
+    align_trailing_comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            column_align: ColumnAlign::default(),
+            tab_size: DEFAULT_TAB_SIZE,
+            blank_lines_between_forms: 1,
+            normalize_spacing: false,
+            align_trailing_comments: false,
+        }
+    }
+}
+
+/// When the timeouts of a `tap-hold` action look like a mistake
+#[derive(Debug, Clone, Copy)]
+struct TapHoldLimits {
+    /// Timeouts above this many milliseconds are hinted as unusually long; 0 disables the check
+    max_timeout_ms: u64,
+    /// A tap timeout this many times the hold timeout is hinted as swapped; 0 disables the check
+    swap_ratio: u64,
+}
+
+impl Default for TapHoldLimits {
+    fn default() -> Self {
+        Self {
+            max_timeout_ms: 2000,
+            swap_ratio: 4,
+        }
+    }
+}
+
+/// Which workspace files are indexed at startup
+#[derive(Debug, Clone)]
+struct IndexOptions {
+    /// Most `.kbd` files indexed; 0 disables the scan
+    max_files: usize,
+    /// Globs, relative to a workspace folder, of files and directories left out
+    exclude: Vec<String>,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            max_files: 500,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Server settings, read from the client's initialization options.
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    /// How long a single parse may run before validation gives up
+    validation_timeout: Duration,
+    /// Fallback directory for resolving `(include ...)` paths
+    config_root: Option<PathBuf>,
+    diagnostics_mode: DiagnosticsMode,
+    diagnostics_trigger: DiagnosticsTrigger,
+    /// Layers with at most this many non-transparent keys get an "unfinished" hint; 0 disables it
+    sparse_layer_threshold: usize,
+    tap_hold: TapHoldLimits,
+    /// Whether to offer the built-in deflayer formatter
+    formatting_enabled: bool,
+    format: FormatOptions,
+    /// Whether to offer the tokens a parse error expected as completions at that error
+    expected_token_completion: bool,
+    /// Whether keywords typed in the wrong case still match, completing to the right case
+    case_insensitive_completion: bool,
+    /// Whether rename also changes the name where comments mention it
+    rename_update_comments: bool,
+    /// Whether the client accepts snippet completions; taken from its capabilities
+    snippet_support: bool,
+    /// Whether the client handles `workspace/diagnostic/refresh`; taken from its capabilities
+    diagnostic_refresh_support: bool,
+    /// Whether the client accepts `LocationLink`s for definitions; taken from its capabilities
+    definition_link_support: bool,
+    /// Installed kanata binary, used to report its version
+    kanata_path: Option<PathBuf>,
+    /// Whether to log the raw parser output and other details of each validation
+    verbose_logging: bool,
+    index: IndexOptions,
+    /// Workspace folders the client opened; taken from its initialize request
+    workspace_roots: Vec<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            validation_timeout: Duration::from_secs(3),
+            config_root: None,
+            diagnostics_mode: DiagnosticsMode::default(),
+            diagnostics_trigger: DiagnosticsTrigger::default(),
+            sparse_layer_threshold: 2,
+            tap_hold: TapHoldLimits::default(),
+            formatting_enabled: true,
+            format: FormatOptions::default(),
+            expected_token_completion: true,
+            case_insensitive_completion: true,
+            rename_update_comments: false,
+            snippet_support: false,
+            diagnostic_refresh_support: false,
+            definition_link_support: false,
+            kanata_path: None,
+            verbose_logging: false,
+            index: IndexOptions::default(),
+            workspace_roots: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_options(options: Option<&serde_json::Value>) -> Self {
+        let mut config = Self::default();
+        if let Some(options) = options {
+            config.apply_options(options);
+        }
+        config
+    }
+    
+    /// Overwrite the settings `options` names, leaving the rest as they are.
+    fn apply_options(&mut self, options: &serde_json::Value) {
+        if let Some(ms) = options.pointer("/validation/timeoutMs").and_then(|v| v.as_u64()) {
+            self.validation_timeout = Duration::from_millis(ms);
+        }
+        
+        if let Some(root) = options.get("configRoot").and_then(|v| v.as_str()) {
+            self.config_root = Some(PathBuf::from(root));
+        }
+        
+        match options.pointer("/diagnostics/mode").and_then(|v| v.as_str()) {
+            Some("push") => self.diagnostics_mode = DiagnosticsMode::Push,
+            Some("pull") => self.diagnostics_mode = DiagnosticsMode::Pull,
+            _ => {}
+        }
+        
+        match options.pointer("/diagnostics/trigger").and_then(|v| v.as_str()) {
+            Some("onType") => self.diagnostics_trigger = DiagnosticsTrigger::Change,
+            Some("onSave") => self.diagnostics_trigger = DiagnosticsTrigger::Save,
+            Some("onOpen") => self.diagnostics_trigger = DiagnosticsTrigger::Open,
+            _ => {}
+        }
+        
+        if let Some(threshold) = options.pointer("/diagnostics/sparseLayerThreshold").and_then(|v| v.as_u64()) {
+            self.sparse_layer_threshold = threshold as usize;
+        }
+        
+        if let Some(ms) = options.pointer("/diagnostics/tapHold/maxTimeoutMs").and_then(|v| v.as_u64()) {
+            self.tap_hold.max_timeout_ms = ms;
+        }
+        if let Some(ratio) = options.pointer("/diagnostics/tapHold/swapRatio").and_then(|v| v.as_u64()) {
+            self.tap_hold.swap_ratio = ratio;
+        }
+        
+        if let Some(enabled) = options.pointer("/completion/expectedTokens").and_then(|v| v.as_bool()) {
+            self.expected_token_completion = enabled;
+        }
+        if let Some(enabled) = options.pointer("/completion/caseInsensitive").and_then(|v| v.as_bool()) {
+            self.case_insensitive_completion = enabled;
+        }
+        
+        if let Some(enabled) = options.pointer("/rename/updateComments").and_then(|v| v.as_bool()) {
+            self.rename_update_comments = enabled;
+        }
+        
+        if let Some(path) = options.get("kanataPath").and_then(|v| v.as_str()) {
+            self.kanata_path = Some(PathBuf::from(path));
+        }
+        
+        if let Some(verbose) = options.pointer("/logging/verbose").and_then(|v| v.as_bool()) {
+            self.verbose_logging = verbose;
+        }
+        
+        if let Some(max_files) = options.pointer("/index/maxFiles").and_then(|v| v.as_u64()) {
+            self.index.max_files = max_files as usize;
+        }
+        if let Some(exclude) = options.pointer("/index/exclude").and_then(|v| v.as_array()) {
+            self.index.exclude = exclude.iter().filter_map(|glob| glob.as_str()).map(String::from).collect();
+        }
+        
+        if let Some(enabled) = options.pointer("/formatting/enabled").and_then(|v| v.as_bool()) {
+            self.formatting_enabled = enabled;
+        }
+        
+        match options.pointer("/formatting/columnAlign").and_then(|v| v.as_str()) {
+            Some("right") => self.format.column_align = ColumnAlign::Right,
+            Some("center") => self.format.column_align = ColumnAlign::Center,
+            _ => {}
+        }
+        
+        if let Some(tab_size) = options.pointer("/formatting/tabSize").and_then(|v| v.as_u64()) {
+            self.format.tab_size = tab_size as usize;
+        }
+        
+        if let Some(lines) = options.pointer("/formatting/blankLinesBetweenForms").and_then(|v| v.as_u64()) {
+            self.format.blank_lines_between_forms = lines as usize;
+        }
+        if let Some(normalize) = options.pointer("/formatting/normalizeSpacing").and_then(|v| v.as_bool()) {
+            self.format.normalize_spacing = normalize;
+        }
+        if let Some(align) = options.pointer("/formatting/alignTrailingComments").and_then(|v| v.as_bool()) {
+            self.format.align_trailing_comments = align;
+        }
+    }
 }
 
+/// References to each symbol, keyed by its kind and name
+type ReferenceCache = HashMap<(SymbolKind, String), Vec<Location>>;
+
+/// Files included by each document, with where each `(include ...)` form starts
+type IncludeMap = HashMap<Url, Vec<(Position, Url)>>;
+
 #[derive(Debug)]
 struct KanataLanguageServer {
     client: Client,
+    config: Arc<RwLock<ServerConfig>>,
+    documents: Arc<RwLock<HashMap<Url, String>>>,
     diagnostics_cache: Arc<RwLock<HashMap<Url, Vec<Diagnostic>>>>,
     symbols_cache: Arc<RwLock<HashMap<Url, DocumentSymbols>>>,
+    defsrc_cache: Arc<RwLock<HashMap<Url, DefsrcCache>>>,
+    /// Files pulled in by each document's `(include ...)` forms
+    includes: Arc<RwLock<IncludeMap>>,
+    /// Reverse of `includes`: the documents that include each file
+    includers: Arc<RwLock<HashMap<Url, HashSet<Url>>>>,
+    /// References found for code lenses, cleared whenever a document is revalidated
+    reference_cache: Arc<RwLock<ReferenceCache>>,
+    /// Trace level the client asked for at startup or through `$/setTrace`
+    trace: Arc<RwLock<TraceValue>>,
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for KanataLanguageServer {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let mut config = ServerConfig::from_options(params.initialization_options.as_ref());
+        config.snippet_support = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+            .and_then(|item| item.snippet_support)
+            .unwrap_or(false);
+        config.diagnostic_refresh_support = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.diagnostic.as_ref())
+            .and_then(|diagnostic| diagnostic.refresh_support)
+            .unwrap_or(false);
+        config.definition_link_support = params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.definition.as_ref())
+            .and_then(|definition| definition.link_support)
+            .unwrap_or(false);
+        config.workspace_roots = params
+            .workspace_folders
+            .iter()
+            .flatten()
+            .map(|folder| &folder.uri)
+            .chain(params.root_uri.as_ref())
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect();
+        config.workspace_roots.dedup();
+        let formatting_enabled = config.formatting_enabled;
+        let diagnostics_mode = config.diagnostics_mode;
+        *self.config.write().await = config;
+        *self.trace.write().await = params.trace.unwrap_or_default();
+        
         Ok(InitializeResult {
             server_info: Some(ServerInfo {
                 name: "kanata-lsp".to_string(),
                 version: Some(env!("CARGO_PKG_VERSION").to_string()),
             }),
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
-                )),
-                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
-                    DiagnosticOptions {
+                // Saves are always reported, so `diagnostics.trigger` can change without a restart
+                text_document_sync: Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+                    open_close: Some(true),
+                    change: Some(TextDocumentSyncKind::FULL),
+                    save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                    ..TextDocumentSyncOptions::default()
+                })),
+                diagnostic_provider: diagnostics_mode.pulls().then(|| {
+                    DiagnosticServerCapabilities::Options(DiagnosticOptions {
                         identifier: Some("kanata".to_string()),
                         inter_file_dependencies: false,
                         workspace_diagnostics: false,
                         work_done_progress_options: WorkDoneProgressOptions::default(),
-                    },
-                )),
+                    })
+                }),
                 definition_provider: Some(OneOf::Left(true)),
+                declaration_provider: Some(DeclarationCapability::Simple(true)),
                 references_provider: Some(OneOf::Left(true)),
                 rename_provider: Some(OneOf::Left(true)),
                 implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
-                document_formatting_provider: Some(OneOf::Left(true)),
+                type_definition_provider: Some(TypeDefinitionProviderCapability::Simple(true)),
+                document_formatting_provider: formatting_enabled.then_some(OneOf::Left(true)),
+                document_range_formatting_provider: formatting_enabled.then_some(OneOf::Left(true)),
+                completion_provider: Some(CompletionOptions::default()),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                code_lens_provider: Some(CodeLensOptions {
+                    resolve_provider: Some(true),
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        "kanata.showDefsrc".to_string(),
+                        "kanata.version".to_string(),
+                        "kanata.newLayer".to_string(),
+                        "kanata.showReferences".to_string(),
+                        "kanata.sortAliases".to_string(),
+                        "kanata.listLayerTransitions".to_string(),
+                        "kanata.layoutReport".to_string(),
+                        "kanata.nextLayerSamePosition".to_string(),
+                        "kanata.previousLayerSamePosition".to_string(),
+                        "kanata.exportLayerGrid".to_string(),
+                    ],
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
                 ..ServerCapabilities::default()
             },
         })
@@ -59,6 +657,35 @@ impl LanguageServer for KanataLanguageServer {
         self.client
             .log_message(MessageType::INFO, "Kanata LSP server initialized")
             .await;
+        
+        // Watch config files so documents can be refreshed when their includes change on disk
+        let watch_options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String("**/*.kbd".to_string()),
+                kind: None,
+            }],
+        };
+        let registration = Registration {
+            id: "kanata-watched-files".to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(watch_options).ok(),
+        };
+        if let Err(e) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(MessageType::WARNING, format!("Failed to register file watchers: {}", e))
+                .await;
+        }
+        
+        // Index the workspace in the background so initialization isn't held up by it
+        let config = self.config.read().await.clone();
+        if config.index.max_files > 0 && !config.workspace_roots.is_empty() {
+            tokio::spawn(Self::index_workspace(
+                self.client.clone(),
+                self.symbols_cache.clone(),
+                config.workspace_roots,
+                config.index,
+            ));
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -66,163 +693,261 @@ impl LanguageServer for KanataLanguageServer {
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        self.validate_document(&params.text_document.uri, &params.text_document.text)
-            .await;
-    }
-
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(change) = params.content_changes.first() {
-            self.validate_document(&params.text_document.uri, &change.text)
+        self.documents.write().await.insert(
+            params.text_document.uri.clone(),
+            params.text_document.text.clone(),
+        );
+        self.defsrc_cache.write().await.remove(&params.text_document.uri);
+        if self.config.read().await.diagnostics_trigger == DiagnosticsTrigger::Save {
+            self.index_document(&params.text_document.uri, &params.text_document.text).await;
+        } else {
+            self.validate_document(&params.text_document.uri, &params.text_document.text)
                 .await;
         }
     }
 
-    async fn goto_definition(
-        &self,
-        params: GotoDefinitionParams,
-    ) -> Result<Option<GotoDefinitionResponse>> {
-        let uri = &params.text_document_position_params.text_document.uri;
-        let position = params.text_document_position_params.position;
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let uri = &params.text_document.uri;
+        if params.content_changes.is_empty() {
+            return;
+        }
         
-        // Get the document text - we'll need to read it from the file
-        let file_path = uri.to_file_path().ok();
-        if file_path.is_none() {
-            return Ok(None);
+        // Apply the changes in order: full-text changes replace the document,
+        // ranged ones splice into it
+        let mut text = self.documents.read().await.get(uri).cloned().unwrap_or_default();
+        let mut symbols_unaffected = true;
+        for change in params.content_changes {
+            symbols_unaffected = symbols_unaffected
+                && self.symbols_cache.read().await.get(uri).is_some_and(|symbols| {
+                    Self::change_keeps_symbols(&text, &change, symbols)
+                });
+            match change.range {
+                Some(range) => {
+                    let start = Self::position_to_offset(&text, range.start);
+                    let end = Self::position_to_offset(&text, range.end).max(start);
+                    text.replace_range(start..end, &change.text);
+                }
+                None => text = change.text,
+            }
+        }
+        
+        self.documents.write().await.insert(uri.clone(), text.clone());
+        self.invalidate_defsrc_layout(uri, &text).await;
+        
+        // Without validation on every change, only the symbols are kept up to date
+        if self.config.read().await.diagnostics_trigger != DiagnosticsTrigger::Change {
+            if !symbols_unaffected {
+                self.index_document(uri, &text).await;
+            }
+            return;
         }
         
-        let text = match std::fs::read_to_string(file_path.as_ref().unwrap()) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
+        // Typing that can't have touched a definition keeps the symbols already extracted
+        let symbols = if symbols_unaffected {
+            self.symbols_cache.write().await.remove(uri)
+        } else {
+            None
         };
+        self.validate_document_with_symbols(uri, &text, symbols).await;
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = &params.text_document.uri;
+        if self.config.read().await.diagnostics_trigger != DiagnosticsTrigger::Save {
+            return;
+        }
         
-        // Get the word at the cursor position
-        let word = Self::get_word_at_position(&text, position);
-        if word.is_empty() {
-            return Ok(None);
+        let text = self.documents.read().await.get(uri).cloned();
+        if let Some(text) = text {
+            self.validate_document(uri, &text).await;
         }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = &params.text_document.uri;
+        self.documents.write().await.remove(uri);
+        self.diagnostics_cache.write().await.remove(uri);
+        self.defsrc_cache.write().await.remove(uri);
+        self.reference_cache.write().await.clear();
         
-        // Check if it's an alias reference (starts with @)
-        if word.starts_with('@') {
-            let alias_name = &word[1..]; // Remove the @
-            let symbols = self.symbols_cache.read().await;
-            if let Some(doc_symbols) = symbols.get(uri) {
-                if let Some(def) = doc_symbols.aliases.get(alias_name) {
-                    return Ok(Some(GotoDefinitionResponse::Scalar(Location {
-                        uri: def.uri.clone(),
-                        range: def.range,
-                    })));
-                }
+        // A file still included by an open document keeps its symbols, as saved on disk
+        let still_included = self.includers.read().await.contains_key(uri);
+        let saved_text = uri.to_file_path().ok().and_then(|path| std::fs::read_to_string(path).ok());
+        match saved_text.filter(|_| still_included) {
+            Some(text) => {
+                self.symbols_cache.write().await.insert(uri.clone(), Self::extract_symbols(uri, &text));
             }
-        } else {
-            // Check if it's a layer reference
-            let symbols = self.symbols_cache.read().await;
-            if let Some(doc_symbols) = symbols.get(uri) {
-                if let Some(def) = doc_symbols.layers.get(&word) {
-                    return Ok(Some(GotoDefinitionResponse::Scalar(Location {
-                        uri: def.uri.clone(),
-                        range: def.range,
-                    })));
-                }
+            None => {
+                self.symbols_cache.write().await.remove(uri);
             }
         }
         
-        Ok(None)
+        // Its own includes no longer need to reach it, and those that nothing else
+        // includes or has open are dropped from the index
+        let included = self.includes.write().await.remove(uri).unwrap_or_default();
+        let mut includers = self.includers.write().await;
+        for dependents in includers.values_mut() {
+            dependents.remove(uri);
+        }
+        includers.retain(|_, dependents| !dependents.is_empty());
+        let orphaned: Vec<Url> = included
+            .into_iter()
+            .map(|(_, include_uri)| include_uri)
+            .filter(|include_uri| !includers.contains_key(include_uri))
+            .collect();
+        drop(includers);
+        
+        let documents = self.documents.read().await;
+        let mut symbols = self.symbols_cache.write().await;
+        for include_uri in orphaned.iter().filter(|include_uri| !documents.contains_key(*include_uri)) {
+            symbols.remove(include_uri);
+        }
+        drop((documents, symbols));
+        
+        // Clear its squiggles from the client's problems list
+        if self.config.read().await.diagnostics_mode.pushes() {
+            self.client.publish_diagnostics(uri.clone(), vec![], None).await;
+        }
     }
 
-    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
-        let uri = &params.text_document_position.text_document.uri;
-        let position = params.text_document_position.position;
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        // Settings may arrive bare or namespaced under the server's name
+        let settings = params
+            .settings
+            .get("kanata-lsp")
+            .unwrap_or(&params.settings);
         
-        // Get the document text
-        let file_path = uri.to_file_path().ok();
-        if file_path.is_none() {
-            return Ok(None);
+        // Zed sends this right after startup even when no settings are set, which mustn't
+        // undo the initialization options; settings that are sent override just themselves
+        if settings.is_null() || settings.as_object().is_some_and(|settings| settings.is_empty()) {
+            return;
         }
+        self.config.write().await.apply_options(settings);
         
-        let text = match std::fs::read_to_string(file_path.as_ref().unwrap()) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
-        };
-        
-        // Get the word at the cursor position
-        let word = Self::get_word_at_position(&text, position);
-        if word.is_empty() {
-            return Ok(None);
+        // Diagnostics depend on settings such as the validation timeout, so refresh them,
+        // unless validation only happens on open or save
+        if self.config.read().await.diagnostics_trigger != DiagnosticsTrigger::Change {
+            return;
         }
+        let documents: Vec<(Url, String)> = self
+            .documents
+            .read()
+            .await
+            .iter()
+            .map(|(uri, text)| (uri.clone(), text.clone()))
+            .collect();
+        for (uri, text) in documents {
+            self.validate_document(&uri, &text).await;
+        }
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        self.reindex_watched_files(&params.changes).await;
         
-        // Determine if it's an alias or layer
-        let (search_word, is_alias) = if word.starts_with('@') {
-            (&word[1..], true)
+        // A newly created file may satisfy a previously missing include, so recheck everything
+        let affected: HashSet<Url> = if params.changes.iter().any(|change| change.typ == FileChangeType::CREATED) {
+            self.documents.read().await.keys().cloned().collect()
         } else {
-            (word.as_str(), false)
+            let includers = self.includers.read().await;
+            params
+                .changes
+                .iter()
+                .filter_map(|change| includers.get(&change.uri))
+                .flatten()
+                .cloned()
+                .collect()
         };
         
-        let mut locations = Vec::new();
+        let mut revalidated = false;
+        for uri in affected {
+            let text = self.documents.read().await.get(&uri).cloned();
+            if let Some(text) = text {
+                self.validate_document(&uri, &text).await;
+                revalidated = true;
+            }
+        }
         
-        // Search through all documents in the cache
-        let symbols = self.symbols_cache.read().await;
-        for (doc_uri, _doc_symbols) in symbols.iter() {
-            // Read the document to find references
-            if let Ok(doc_path) = doc_uri.to_file_path() {
-                if let Ok(doc_text) = std::fs::read_to_string(&doc_path) {
-                    let lines: Vec<&str> = doc_text.lines().collect();
-                    
-                    for (line_idx, line) in lines.iter().enumerate() {
-                        if is_alias {
-                            // Look for @word references
-                            let search_pattern = format!("@{}", search_word);
-                            let mut start = 0;
-                            while let Some(pos) = line[start..].find(&search_pattern) {
-                                let actual_pos = start + pos;
-                                locations.push(Location {
-                                    uri: doc_uri.clone(),
-                                    range: Range {
-                                        start: Position {
-                                            line: line_idx as u32,
-                                            character: actual_pos as u32,
-                                        },
-                                        end: Position {
-                                            line: line_idx as u32,
-                                            character: (actual_pos + search_pattern.len()) as u32,
-                                        },
-                                    },
-                                });
-                                start = actual_pos + 1;
-                            }
-                        } else {
-                            // Look for layer name references (without @)
-                            // This is trickier - we need to find word boundaries
-                            for (char_idx, _) in line.char_indices() {
-                                let remaining = &line[char_idx..];
-                                if remaining.starts_with(search_word) {
-                                    // Check if it's a word boundary
-                                    let before_ok = char_idx == 0 || !line.chars().nth(char_idx - 1).unwrap().is_alphanumeric();
-                                    let after_idx = char_idx + search_word.len();
-                                    let after_ok = after_idx >= line.len() || !line.chars().nth(after_idx).map(|c| c.is_alphanumeric()).unwrap_or(false);
-                                    
-                                    if before_ok && after_ok {
-                                        locations.push(Location {
-                                            uri: doc_uri.clone(),
-                                            range: Range {
-                                                start: Position {
-                                                    line: line_idx as u32,
-                                                    character: char_idx as u32,
-                                                },
-                                                end: Position {
-                                                    line: line_idx as u32,
-                                                    character: (char_idx + search_word.len()) as u32,
-                                                },
-                                            },
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+        // Pull clients only see the new diagnostics once they ask again, so tell them to
+        let config = self.config.read().await.clone();
+        if revalidated
+            && config.diagnostics_mode.pulls()
+            && config.diagnostic_refresh_support
+            && let Err(e) = self.client.workspace_diagnostic_refresh().await
+        {
+            self.client
+                .log_message(MessageType::WARNING, format!("Failed to refresh diagnostics: {}", e))
+                .await;
+        }
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        
+        // Definition points at the name token itself. Clients that take links also get
+        // the whole form, so a peek shows the definition rather than just its name.
+        let link_support = self.config.read().await.definition_link_support;
+        Ok(self.find_definition(uri, position).await.map(|def| {
+            if link_support {
+                GotoDefinitionResponse::Link(vec![LocationLink {
+                    origin_selection_range: None,
+                    target_uri: def.uri,
+                    target_range: def.full_range,
+                    target_selection_range: def.range,
+                }])
+            } else {
+                GotoDefinitionResponse::Scalar(Location {
+                    uri: def.uri,
+                    range: def.range,
+                })
             }
+        }))
+    }
+
+    async fn goto_declaration(
+        &self,
+        params: request::GotoDeclarationParams,
+    ) -> Result<Option<request::GotoDeclarationResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        
+        // Declaration points at the opening keyword of the enclosing form,
+        // e.g. `(defalias` rather than the alias name
+        Ok(self.find_definition(uri, position).await.map(|def| {
+            request::GotoDeclarationResponse::Scalar(Location {
+                uri: def.uri,
+                range: def.keyword_range,
+            })
+        }))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        
+        // Get the document text, from the editor if it is open
+        let text = match self.document_text(uri).await {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        
+        // Get the word at the cursor position, unless it is inside a comment. Inside a
+        // string the word is the whole string, which only matches a quoted name.
+        let word = Self::get_word_at_position(&text, position);
+        let masked = Self::mask_comments(&text);
+        if word.is_empty() || Self::get_word_at_position(&masked, position) != word {
+            return Ok(None);
         }
         
+        // Determine which kind of symbol it is
+        let (kind, search_word) = Self::classify_word_at(uri, position, &word, &*self.symbols_cache.read().await);
+        let locations = self
+            .symbol_references(kind, search_word, params.context.include_declaration)
+            .await;
+        
         if locations.is_empty() {
             Ok(None)
         } else {
@@ -235,15 +960,10 @@ impl LanguageServer for KanataLanguageServer {
         let position = params.text_document_position.position;
         let new_name = &params.new_name;
         
-        // Get the document text
-        let file_path = uri.to_file_path().ok();
-        if file_path.is_none() {
-            return Ok(None);
-        }
-        
-        let text = match std::fs::read_to_string(file_path.as_ref().unwrap()) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
+        // Get the document text, from the editor if it is open
+        let text = match self.document_text(uri).await {
+            Some(t) => t,
+            None => return Ok(None),
         };
         
         // Get the word at the cursor position
@@ -252,6 +972,31 @@ impl LanguageServer for KanataLanguageServer {
             return Ok(None);
         }
         
+        // Refuse to rename onto a name that is already defined, since that would
+        // leave two definitions of the same symbol
+        let symbols = self.symbols_cache.read().await;
+        let (kind, old_name) = Self::classify_word_at(uri, position, &word, &symbols);
+        let target_name = new_name.strip_prefix('@').unwrap_or(new_name);
+        
+        // Only a name that is already quoted can take spaces, and nothing can take quotes or parens
+        let has_space = |name: &str| name.contains(char::is_whitespace);
+        if target_name.contains(['"', '(', ')']) || (has_space(target_name) && !has_space(old_name)) {
+            return Err(Error::invalid_params(format!("`{}` is not a valid name here", target_name)));
+        }
+        if target_name != old_name {
+            let collides = symbols
+                .values()
+                .any(|doc_symbols| doc_symbols.definitions(kind).contains_key(target_name));
+            if collides {
+                return Err(Error::invalid_params(format!(
+                    "{} `{}` already exists",
+                    kind.label(),
+                    target_name
+                )));
+            }
+        }
+        drop(symbols);
+        
         // Find all references to this symbol
         let references_params = ReferenceParams {
             text_document_position: params.text_document_position.clone(),
@@ -267,8 +1012,15 @@ impl LanguageServer for KanataLanguageServer {
             None => return Ok(None),
         };
         
-        // Determine if we're renaming an alias (and need to add/remove @)
-        let is_alias = word.starts_with('@');
+        // Alias references are written `@name` but the definition is the bare name,
+        // so each edit site gets the prefix it had
+        let symbols = self.symbols_cache.read().await;
+        let is_definition = |location: &Location| {
+            symbols
+                .get(&location.uri)
+                .and_then(|doc_symbols| doc_symbols.definitions(kind).get(old_name))
+                .is_some_and(|def| def.range == location.range)
+        };
         
         // Create text edits for all references
         let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
@@ -276,10 +1028,10 @@ impl LanguageServer for KanataLanguageServer {
         for location in locations {
             let edit = TextEdit {
                 range: location.range,
-                new_text: if is_alias {
-                    format!("@{}", new_name)
+                new_text: if kind == SymbolKind::Alias && !is_definition(&location) {
+                    format!("@{}", target_name)
                 } else {
-                    new_name.to_string()
+                    target_name.to_string()
                 },
             };
             
@@ -287,14 +1039,59 @@ impl LanguageServer for KanataLanguageServer {
                 .or_insert_with(Vec::new)
                 .push(edit);
         }
+        drop(symbols);
         
-        Ok(Some(WorkspaceEdit {
+        // Comments that mention the name, e.g. a header naming the layer, can follow along
+        if self.config.read().await.rename_update_comments {
+            for (doc_uri, edits) in changes.iter_mut() {
+                let Some(doc_text) = self.document_text(doc_uri).await else {
+                    continue;
+                };
+                edits.extend(Self::comment_occurrences(&doc_text, old_name).into_iter().map(|range| TextEdit {
+                    range,
+                    new_text: target_name.to_string(),
+                }));
+            }
+        }
+        for edits in changes.values_mut() {
+            Self::disjoint_edits(edits);
+        }
+        
+        Ok(Some(WorkspaceEdit {
             changes: Some(changes),
             document_changes: None,
             change_annotations: None,
         }))
     }
 
+    async fn goto_type_definition(
+        &self,
+        params: request::GotoTypeDefinitionParams,
+    ) -> Result<Option<request::GotoTypeDefinitionResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        
+        let documents = self.documents.read().await;
+        let text = match documents.get(uri) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        
+        // A deflayer item's "type" is the physical key it sits on in defsrc
+        let masked = Self::mask_comments(text);
+        let offset = Self::position_to_offset(&masked, position);
+        
+        Ok(Self::defsrc_item_for(&masked, offset).map(|(start, end)| {
+            request::GotoTypeDefinitionResponse::Scalar(Location {
+                uri: uri.clone(),
+                range: Range {
+                    start: Self::offset_to_position(&masked, start),
+                    end: Self::offset_to_position(&masked, end),
+                },
+            })
+        }))
+    }
+
     async fn goto_implementation(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
         // For Kanata, implementation is the same as definition
         // (finding where aliases/layers are defined)
@@ -304,38 +1101,576 @@ impl LanguageServer for KanataLanguageServer {
     async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
         let uri = &params.text_document.uri;
         
-        // Get the document text
-        let file_path = uri.to_file_path().ok();
-        if file_path.is_none() {
+        // Leave formatting to external tools when disabled
+        if !self.config.read().await.formatting_enabled {
             return Ok(None);
         }
         
-        let text = match std::fs::read_to_string(file_path.unwrap()) {
-            Ok(t) => t,
-            Err(_) => return Ok(None),
+        // Get the document text, from the editor if it is open
+        let text = match self.document_text(uri).await {
+            Some(t) => t,
+            None => return Ok(None),
         };
         
-        // Format the document
-        let formatted = Self::format_document(&text);
+        // Format the document, with the editor's own tab width
+        let mut format_options = self.config.read().await.format.clone();
+        format_options.tab_size = params.options.tab_size as usize;
+        let defsrc_layout = match self.defsrc_layout(uri, &text, format_options.tab_size).await {
+            Some(layout) => layout,
+            None => return Ok(None), // No defsrc found, no formatting
+        };
+        let formatted = Self::format_document(&text, &defsrc_layout, &format_options);
         
         if formatted == text {
             // No changes needed
             return Ok(None);
         }
         
-        // Calculate the range covering the entire document
-        let line_count = text.lines().count() as u32;
-        let last_line_len = text.lines().last().map(|l| l.len()).unwrap_or(0) as u32;
-        
+        // Replace the entire document, through any final newline: `format_document` keeps
+        // the original's trailing newline, so stopping before it would double it
         Ok(Some(vec![TextEdit {
             range: Range {
                 start: Position { line: 0, character: 0 },
-                end: Position { line: line_count.saturating_sub(1), character: last_line_len },
+                end: Self::offset_to_position(&text, text.len()),
             },
             new_text: formatted,
         }]))
     }
 
+    async fn range_formatting(&self, params: DocumentRangeFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let uri = &params.text_document.uri;
+        
+        if !self.config.read().await.formatting_enabled {
+            return Ok(None);
+        }
+        
+        let text = match self.document_text(uri).await {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let mut format_options = self.config.read().await.format.clone();
+        format_options.tab_size = params.options.tab_size as usize;
+        let defsrc_layout = match self.defsrc_layout(uri, &text, format_options.tab_size).await {
+            Some(layout) => layout,
+            None => return Ok(None),
+        };
+        
+        // Edit only the selected layers, so the rest of the document and the view stay put
+        let edits = Self::range_format_edits(&text, &defsrc_layout, &format_options, params.range);
+        if edits.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(edits))
+        }
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        
+        let documents = self.documents.read().await;
+        let text = match documents.get(uri) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        
+        // Work out which form the cursor is in, ignoring comments
+        let masked = Self::mask_comments(text);
+        let offset = Self::position_to_offset(&masked, position);
+        
+        let context = Self::completion_context(&masked, offset);
+        let mut items = match context {
+            Some(CompletionContext::Keycode) => {
+                let mut items = Self::keycode_completions();
+                items.extend(Self::local_key_completions(&masked));
+                items
+            }
+            Some(CompletionContext::Layer) => self.layer_completions(uri).await,
+            Some(CompletionContext::Action) => {
+                Self::action_completions(self.config.read().await.snippet_support)
+            }
+            Some(CompletionContext::Alias) => self.alias_completions(uri, &masked, offset).await,
+            Some(CompletionContext::DefcfgKey) => Self::defcfg_key_completions(),
+            Some(CompletionContext::DefcfgValue) => Self::defcfg_value_completions(&masked, offset),
+            None if self.config.read().await.expected_token_completion => {
+                self.expected_token_completions(uri, position).await
+            }
+            None => return Ok(None),
+        };
+        
+        // Kanata keywords are lowercase, but let a word typed in another case still find them
+        let keyword_context = matches!(
+            context,
+            Some(
+                CompletionContext::Keycode
+                    | CompletionContext::Action
+                    | CompletionContext::DefcfgKey
+                    | CompletionContext::DefcfgValue
+            )
+        );
+        if keyword_context && self.config.read().await.case_insensitive_completion {
+            Self::correct_typed_case(&mut items, &masked, offset);
+        }
+        
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        
+        // Cloned so the lock isn't held while looking up definitions
+        let text = match self.documents.read().await.get(uri) {
+            Some(t) => t.clone(),
+            None => return Ok(None),
+        };
+        
+        let masked = Self::mask_comments(&text);
+        let offset = Self::position_to_offset(&masked, position);
+        let (start, end) = match Self::defsrc_item_at(&masked, offset) {
+            Some(span) => span,
+            None => {
+                let alias = self.alias_hover(uri, &text, &masked, position).await;
+                return Ok(Self::with_defsrc_key(&masked, offset, alias));
+            }
+        };
+        let name = &masked[start..end];
+        let value = if let Some(keycode) = keycodes::lookup(name) {
+            let mut value = format!("**{}** — {}", keycode.name, keycode.description);
+            if !keycode.aliases.is_empty() {
+                let aliases: Vec<String> = keycode.aliases.iter().map(|alias| format!("`{}`", alias)).collect();
+                value.push_str(&format!("\n\nAlso written {}", aliases.join(", ")));
+            }
+            value
+        } else {
+            let definitions: Vec<String> = Self::local_keys(&masked)
+                .into_iter()
+                .filter(|&(_, local, _)| local == name)
+                .map(|(form, _, code)| format!("key code `{}` in `{}`", code, form))
+                .collect();
+            if definitions.is_empty() {
+                return Ok(None);
+            }
+            format!("**{}** — local key, {}", name, definitions.join(", "))
+        };
+        
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(Range {
+                start: Self::offset_to_position(&masked, start),
+                end: Self::offset_to_position(&masked, end),
+            }),
+        }))
+    }
+
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        let documents = self.documents.read().await;
+        let text = match documents.get(&params.text_document.uri) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        
+        let masked = Self::mask_comments(text);
+        let tokens = Self::tokenize(&masked);
+        
+        Ok(Some(
+            params
+                .positions
+                .iter()
+                .map(|&position| {
+                    let offset = Self::position_to_offset(&masked, position);
+                    Self::selection_range_at(&masked, &tokens, offset)
+                })
+                .collect(),
+        ))
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = &params.text_document.uri;
+        
+        // Cloned so the lock isn't held while the defsrc layout is looked up
+        let text = match self.documents.read().await.get(uri) {
+            Some(t) => t.clone(),
+            None => return Ok(None),
+        };
+        let text = &text;
+        
+        let masked = Self::mask_comments(text);
+        let offset = Self::position_to_offset(&masked, params.range.start);
+        let mut actions = Vec::new();
+        
+        // Quick fixes for non-canonical keycodes carry the replacement in their data
+        for diagnostic in &params.context.diagnostics {
+            let canonical = match diagnostic.data.as_ref().and_then(|data| data.get("canonical")) {
+                Some(serde_json::Value::String(canonical)) => canonical,
+                _ => continue,
+            };
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Replace with `{}`", canonical),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        uri.clone(),
+                        vec![TextEdit {
+                            range: diagnostic.range,
+                            new_text: canonical.clone(),
+                        }],
+                    )])),
+                    ..WorkspaceEdit::default()
+                }),
+                is_preferred: Some(true),
+                ..CodeAction::default()
+            }));
+        }
+        
+        // Stray tokens carry the range to delete in theirs
+        for diagnostic in &params.context.diagnostics {
+            let remove = diagnostic
+                .data
+                .as_ref()
+                .and_then(|data| data.get("remove"))
+                .and_then(|remove| serde_json::from_value::<Range>(remove.clone()).ok());
+            let Some(remove) = remove else {
+                continue;
+            };
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Remove stray token".to_string(),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        uri.clone(),
+                        vec![TextEdit {
+                            range: remove,
+                            new_text: String::new(),
+                        }],
+                    )])),
+                    ..WorkspaceEdit::default()
+                }),
+                is_preferred: Some(true),
+                ..CodeAction::default()
+            }));
+        }
+        
+        if let Some(edit) = Self::split_defalias_edit(text, &masked, offset) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Split defalias group".to_string(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                    ..WorkspaceEdit::default()
+                }),
+                ..CodeAction::default()
+            }));
+        }
+        
+        if let Some(edits) = Self::blank_layer_edits(&masked, offset) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Replace bindings with transparent".to_string(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), edits)])),
+                    ..WorkspaceEdit::default()
+                }),
+                ..CodeAction::default()
+            }));
+        }
+        
+        let format_options = self.config.read().await.format.clone();
+        let layout = self.defsrc_layout(uri, text, format_options.tab_size).await;
+        if let Some((title, edit)) = Self::tap_hold_toggle_edit(text, layout.as_deref(), &format_options, offset) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: title.to_string(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                    ..WorkspaceEdit::default()
+                }),
+                ..CodeAction::default()
+            }));
+        }
+        
+        Ok((!actions.is_empty()).then_some(actions))
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        let uri = &params.text_document.uri;
+        
+        let documents = self.documents.read().await;
+        let text = match documents.get(uri) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        
+        let masked = Self::mask_comments(text);
+        let mut lenses = Self::layer_length_lenses(uri, &masked);
+        
+        // Reference counts are filled in by code_lens_resolve
+        if let Some(doc_symbols) = self.symbols_cache.read().await.get(uri) {
+            for kind in [SymbolKind::Alias, SymbolKind::Layer] {
+                for (name, def) in doc_symbols.definitions(kind) {
+                    lenses.push(CodeLens {
+                        range: def.range,
+                        command: None,
+                        data: Some(serde_json::json!({ "kind": kind.label(), "name": name })),
+                    });
+                }
+            }
+        }
+        
+        Ok(Some(lenses))
+    }
+
+    async fn code_lens_resolve(&self, mut lens: CodeLens) -> Result<CodeLens> {
+        let data = match (&lens.command, &lens.data) {
+            (None, Some(data)) => data,
+            _ => return Ok(lens),
+        };
+        let kind = match data.get("kind").and_then(|kind| kind.as_str()) {
+            Some("alias") => SymbolKind::Alias,
+            Some("layer") => SymbolKind::Layer,
+            _ => return Err(Error::invalid_params("unknown code lens kind")),
+        };
+        let name = data
+            .get("name")
+            .and_then(|name| name.as_str())
+            .ok_or_else(|| Error::invalid_params("code lens is missing a name"))?
+            .to_string();
+        
+        let cached = self.reference_cache.read().await.get(&(kind, name.clone())).cloned();
+        let locations = match cached {
+            Some(locations) => locations,
+            None => {
+                let locations = self.symbol_references(kind, &name, false).await;
+                self.reference_cache
+                    .write()
+                    .await
+                    .insert((kind, name), locations.clone());
+                locations
+            }
+        };
+        
+        let title = match locations.len() {
+            1 => "1 reference".to_string(),
+            count => format!("{} references", count),
+        };
+        lens.command = Some(Command {
+            title,
+            command: "kanata.showReferences".to_string(),
+            arguments: Some(vec![serde_json::json!(locations)]),
+        });
+        Ok(lens)
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<serde_json::Value>> {
+        match params.command.as_str() {
+            "kanata.showDefsrc" => {
+                let location: Location = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| Error::invalid_params("expected a defsrc location"))?;
+                
+                self.client
+                    .show_document(ShowDocumentParams {
+                        uri: location.uri,
+                        external: None,
+                        take_focus: Some(true),
+                        selection: Some(location.range),
+                    })
+                    .await?;
+                Ok(None)
+            }
+            "kanata.showReferences" => {
+                // Servers can't open a references list, so jump to the first one
+                let locations: Vec<Location> = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| Error::invalid_params("expected a list of locations"))?;
+                
+                if let Some(location) = locations.into_iter().next() {
+                    self.client
+                        .show_document(ShowDocumentParams {
+                            uri: location.uri,
+                            external: None,
+                            take_focus: Some(true),
+                            selection: Some(location.range),
+                        })
+                        .await?;
+                }
+                Ok(None)
+            }
+            "kanata.version" => {
+                let report = self.version_report().await;
+                self.client.show_message(MessageType::INFO, &report).await;
+                Ok(Some(serde_json::Value::String(report)))
+            }
+            "kanata.newLayer" => {
+                let mut arguments = params.arguments.into_iter();
+                let (uri, name) = match (arguments.next(), arguments.next()) {
+                    (Some(uri), Some(serde_json::Value::String(name))) => match serde_json::from_value::<Url>(uri) {
+                        Ok(uri) => (uri, name),
+                        Err(_) => return Err(Error::invalid_params("expected a document URI")),
+                    },
+                    _ => return Err(Error::invalid_params("expected a document URI and a layer name")),
+                };
+                
+                let edit = self.new_layer_edit(&uri, &name).await?;
+                self.client
+                    .apply_edit(WorkspaceEdit {
+                        changes: Some(HashMap::from([(uri, vec![edit])])),
+                        ..WorkspaceEdit::default()
+                    })
+                    .await?;
+                Ok(None)
+            }
+            "kanata.sortAliases" => {
+                let uri: Url = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| Error::invalid_params("expected a document URI"))?;
+                let text = match self.documents.read().await.get(&uri) {
+                    Some(text) => text.clone(),
+                    None => return Err(Error::invalid_params("document is not open")),
+                };
+                
+                let edits = Self::sort_aliases_edits(&text)?;
+                if !edits.is_empty() {
+                    self.client
+                        .apply_edit(WorkspaceEdit {
+                            changes: Some(HashMap::from([(uri, edits)])),
+                            ..WorkspaceEdit::default()
+                        })
+                        .await?;
+                }
+                Ok(None)
+            }
+            "kanata.listLayerTransitions" => {
+                let transitions = self.layer_transitions().await;
+                let lines: Vec<String> = transitions
+                    .iter()
+                    .map(|transition| {
+                        let file = transition.location.uri.path().rsplit('/').next().unwrap_or_default();
+                        let position = transition.location.range.start;
+                        format!(
+                            "{}:{}:{} {} -> {}{}",
+                            file,
+                            position.line + 1,
+                            position.character + 1,
+                            transition.action,
+                            transition.layer,
+                            if transition.defined { "" } else { " (undefined)" },
+                        )
+                    })
+                    .collect();
+                let message = if lines.is_empty() {
+                    "No layer transitions found".to_string()
+                } else {
+                    lines.join("\n")
+                };
+                let undefined = transitions.iter().any(|transition| !transition.defined);
+                let message_type = if undefined { MessageType::WARNING } else { MessageType::INFO };
+                self.client.show_message(message_type, message).await;
+                
+                let result: Vec<serde_json::Value> = transitions
+                    .into_iter()
+                    .map(|transition| {
+                        serde_json::json!({
+                            "location": transition.location,
+                            "action": transition.action,
+                            "layer": transition.layer,
+                            "defined": transition.defined,
+                        })
+                    })
+                    .collect();
+                Ok(Some(serde_json::Value::Array(result)))
+            }
+            "kanata.layoutReport" => {
+                let uri: Url = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| Error::invalid_params("expected a document URI"))?;
+                let text = match self.documents.read().await.get(&uri) {
+                    Some(text) => text.clone(),
+                    None => return Err(Error::invalid_params("document is not open")),
+                };
+                
+                let report = Self::layout_report(&Self::mask_comments(&text));
+                self.client.show_message(MessageType::INFO, &report).await;
+                Ok(Some(serde_json::Value::String(report)))
+            }
+            command @ ("kanata.nextLayerSamePosition" | "kanata.previousLayerSamePosition") => {
+                let forward = command == "kanata.nextLayerSamePosition";
+                let position: TextDocumentPositionParams = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| Error::invalid_params("expected a document position"))?;
+                let uri = position.text_document.uri;
+                let text = match self.documents.read().await.get(&uri) {
+                    Some(text) => text.clone(),
+                    None => return Err(Error::invalid_params("document is not open")),
+                };
+                
+                let masked = Self::mask_comments(&text);
+                let offset = Self::position_to_offset(&masked, position.position);
+                let range = match Self::same_key_in_adjacent_layer(&masked, offset, forward) {
+                    Some(range) => range,
+                    None => return Ok(None),
+                };
+                let location = Location { uri, range };
+                self.client
+                    .show_document(ShowDocumentParams {
+                        uri: location.uri.clone(),
+                        external: None,
+                        take_focus: Some(true),
+                        selection: Some(location.range),
+                    })
+                    .await?;
+                Ok(Some(serde_json::json!(location)))
+            }
+            "kanata.exportLayerGrid" => {
+                let mut arguments = params.arguments.into_iter();
+                let (uri, name) = match (arguments.next(), arguments.next()) {
+                    (Some(uri), Some(serde_json::Value::String(name))) => match serde_json::from_value::<Url>(uri) {
+                        Ok(uri) => (uri, name),
+                        Err(_) => return Err(Error::invalid_params("expected a document URI")),
+                    },
+                    _ => return Err(Error::invalid_params("expected a document URI and a layer name")),
+                };
+                let text = match self.documents.read().await.get(&uri) {
+                    Some(text) => text.clone(),
+                    None => return Err(Error::invalid_params("document is not open")),
+                };
+                
+                let edit = Self::layer_grid_edit(&text, &name)?;
+                self.client
+                    .apply_edit(WorkspaceEdit {
+                        changes: Some(HashMap::from([(uri, vec![edit])])),
+                        ..WorkspaceEdit::default()
+                    })
+                    .await?;
+                Ok(None)
+            }
+            command => Err(Error::invalid_params(format!("unknown command `{}`", command))),
+        }
+    }
+
     async fn diagnostic(
         &self,
         params: DocumentDiagnosticParams,
@@ -361,629 +1696,4455 @@ impl LanguageServer for KanataLanguageServer {
 }
 
 impl KanataLanguageServer {
-    fn format_document(text: &str) -> String {
-        // Parse defsrc layout
-        let defsrc_layout = match Self::parse_defsrc_layout(text) {
-            Some(layout) => layout,
-            None => return text.to_string(), // No defsrc found, no formatting
-        };
-        
-        // Apply layout to all deflayers
-        Self::apply_defsrc_layout_to_deflayers(text, &defsrc_layout)
+    /// Blank out `;;` line comments and `#| ... |#` block comments.
+    ///
+    /// Every commented byte is replaced with a space while newlines are kept,
+    /// so line numbers and byte columns in the result match the original text.
+    /// All structural scanning (symbols, formatting) runs on the masked text.
+    fn mask_comments(text: &str) -> String {
+        Self::mask(text, false)
     }
     
-    fn parse_defsrc_layout(text: &str) -> Option<Vec<Vec<usize>>> {
-        let lines: Vec<&str> = text.lines().collect();
-        let mut in_defsrc = false;
-        let mut defsrc_items: Vec<String> = Vec::new();
-        let mut paren_depth = 0;
+    /// Like `mask_comments`, but also fill the inside of string literals with `_`.
+    ///
+    /// A string stays a single atom of the same length, so token positions don't
+    /// shift, but nothing quoted can match a symbol name. Reference scans use this.
+    fn mask_comments_and_strings(text: &str) -> String {
+        Self::mask(text, true)
+    }
+    
+    fn mask(text: &str, strings: bool) -> String {
+        let bytes = text.as_bytes();
+        let mut masked = bytes.to_vec();
+        let mut i = 0;
         
-        for line in lines {
-            let trimmed = line.trim();
-            
-            if trimmed.starts_with("(defsrc") {
-                in_defsrc = true;
-                paren_depth = 1;
-                // Extract content after (defsrc
-                let content = trimmed.trim_start_matches("(defsrc").trim();
-                if !content.is_empty() && !content.starts_with(')') {
-                    let mut current = String::new();
-                    for ch in content.chars() {
-                        if ch == '(' {
-                            paren_depth += 1;
-                        } else if ch == ')' {
-                            paren_depth -= 1;
-                            if paren_depth == 0 {
-                                if !current.trim().is_empty() {
-                                    defsrc_items.push(current.clone());
-                                }
-                                in_defsrc = false;
-                                break;
-                            }
-                        } else if ch.is_whitespace() && paren_depth == 1 {
-                            if !current.trim().is_empty() {
-                                defsrc_items.push(current.clone());
-                                current.clear();
-                            }
-                        } else {
-                            current.push(ch);
-                        }
-                    }
-                    if !current.trim().is_empty() && in_defsrc {
-                        defsrc_items.push(current);
+        while i < bytes.len() {
+            if bytes[i..].starts_with(b"#|") {
+                // Block comment runs until the closing |# (or end of file)
+                let end = text[i + 2..]
+                    .find("|#")
+                    .map(|pos| i + 2 + pos + 2)
+                    .unwrap_or(bytes.len());
+                Self::blank(text, &mut masked, i..end, b' ');
+                i = end;
+            } else if bytes[i..].starts_with(b";;") {
+                // Line comment runs until the end of the line
+                let end = text[i..]
+                    .find(['\r', '\n'])
+                    .map(|pos| i + pos)
+                    .unwrap_or(bytes.len());
+                Self::blank(text, &mut masked, i..end, b' ');
+                i = end;
+            } else if let Some((open, close)) = Self::string_delimiters(&bytes[i..]) {
+                // Comment markers inside a string don't start a comment. An unterminated
+                // quote is left alone rather than swallowing the rest of the file.
+                let contents = i + open.len();
+                let end = match text[contents..].find(close) {
+                    Some(pos) => contents + pos,
+                    None => {
+                        i += 1;
+                        continue;
                     }
+                };
+                if strings {
+                    Self::blank(text, &mut masked, contents..end, b'_');
                 }
-                continue;
+                i = end + close.len();
+            } else {
+                i += 1;
             }
-            
-            if in_defsrc {
-                for ch in trimmed.chars() {
-                    if ch == '(' {
-                        paren_depth += 1;
-                    } else if ch == ')' {
-                        paren_depth -= 1;
-                        if paren_depth == 0 {
-                            in_defsrc = false;
-                            break;
-                        }
-                    }
-                }
-                
-                if in_defsrc && paren_depth == 1 {
-                    // Parse items from this line
-                    let mut current = String::new();
-                    for ch in trimmed.chars() {
-                        if ch == ')' {
-                            if !current.trim().is_empty() {
-                                defsrc_items.push(current.clone());
-                            }
-                            in_defsrc = false;
-                            break;
-                        } else if ch.is_whitespace() {
-                            if !current.trim().is_empty() {
-                                defsrc_items.push(current.clone());
-                                current.clear();
-                            }
-                        } else {
-                            current.push(ch);
-                        }
-                    }
-                    if !current.trim().is_empty() && in_defsrc {
-                        defsrc_items.push(current);
-                    }
+        }
+        
+        // Only whole characters were replaced, so this is valid UTF-8
+        String::from_utf8(masked).unwrap_or_else(|_| text.to_string())
+    }
+    
+    /// Blank `text[range]` in `masked`, keeping line breaks.
+    ///
+    /// ASCII becomes `filler`, and anything wider becomes whitespace of the same size in
+    /// both bytes and UTF-16 units, so offsets and positions in the masked text still
+    /// match the original's.
+    fn blank(text: &str, masked: &mut [u8], range: std::ops::Range<usize>, filler: u8) {
+        for (idx, ch) in text[range.clone()].char_indices() {
+            let idx = range.start + idx;
+            let replacement = match ch.len_utf8() {
+                _ if ch == '\n' || ch == '\r' => continue,
+                1 => {
+                    masked[idx] = filler;
+                    continue;
                 }
-            }
+                2 => "\u{a0}",
+                3 => "\u{3000}",
+                _ => "\u{a0}\u{a0}",
+            };
+            masked[idx..idx + ch.len_utf8()].copy_from_slice(replacement.as_bytes());
+        }
+    }
+    
+    /// Opening and closing delimiters of a `"..."` or `r#"..."#` string starting at `bytes`.
+    fn string_delimiters(bytes: &[u8]) -> Option<(&'static str, &'static str)> {
+        if bytes.starts_with(b"r#\"") {
+            Some(("r#\"", "\"#"))
+        } else if bytes.starts_with(b"\"") {
+            Some(("\"", "\""))
+        } else {
+            None
         }
+    }
+    
+    async fn find_definition(&self, uri: &Url, position: Position) -> Option<Definition> {
+        // Get the document text, from the editor if it is open
+        let text = self.document_text(uri).await?;
         
-        if defsrc_items.is_empty() {
+        // Get the word at the cursor position
+        let word = Self::get_word_at_position(&text, position);
+        if word.is_empty() {
             return None;
         }
         
-        // Calculate layout (grapheme widths for each item)
-        let layout: Vec<Vec<usize>> = defsrc_items
-            .iter()
-            .map(|item| vec![item.graphemes(true).count()])
-            .collect();
+        // A request can arrive before validation indexed an open document, so index it
+        // now rather than find nothing; validation replaces the entry once it runs
+        let is_open = self.documents.read().await.contains_key(uri);
+        if is_open && !self.symbols_cache.read().await.contains_key(uri) {
+            let doc_symbols = Self::extract_symbols(uri, &text);
+            self.symbols_cache.write().await.entry(uri.clone()).or_insert(doc_symbols);
+        }
         
-        Some(layout)
+        let symbols = self.symbols_cache.read().await;
+        let (kind, name) = Self::classify_word(&word, &symbols);
+        
+        let includes = self.includes.read().await;
+        Self::definition_kanata_uses(uri, &includes, &symbols, kind, name)
     }
     
-    fn apply_defsrc_layout_to_deflayers(text: &str, layout: &[Vec<usize>]) -> String {
-        let lines: Vec<&str> = text.lines().collect();
-        let mut result = Vec::new();
-        let mut i = 0;
+    /// The definition of `name` Kanata actually uses, looking in `uri` and the files it includes.
+    ///
+    /// Kanata reads an included file's forms where its `(include ...)` form appears and
+    /// rejects a second definition of the same name, so of several definitions the one
+    /// it keeps is the first in that reading order, whether it is in the document or an
+    /// included file. Nested includes aren't followed.
+    fn definition_kanata_uses(
+        uri: &Url,
+        includes: &IncludeMap,
+        symbols: &HashMap<Url, DocumentSymbols>,
+        kind: SymbolKind,
+        name: &str,
+    ) -> Option<Definition> {
+        let own = symbols
+            .get(uri)
+            .and_then(|doc_symbols| doc_symbols.definitions(kind).get(name))
+            .map(|def| (def.range.start, def));
+        let included = includes.get(uri).into_iter().flatten().filter_map(|(include_start, include_uri)| {
+            let def = symbols.get(include_uri)?.definitions(kind).get(name)?;
+            Some((*include_start, def))
+        });
         
-        while i < lines.len() {
-            let line = lines[i];
-            let trimmed = line.trim();
-            
-            if trimmed.starts_with("(deflayer") {
-                // Format this deflayer
-                let formatted_deflayer = Self::format_deflayer(&lines, i, layout);
-                result.push(formatted_deflayer.0);
-                i = formatted_deflayer.1;
-            } else {
-                result.push(line.to_string());
-                i += 1;
+        own.into_iter()
+            .chain(included)
+            .min_by_key(|&(read_at, _)| read_at)
+            .map(|(_, def)| def.clone())
+    }
+    
+    /// "↔ defsrc" and "N/M keys" lenses above each deflayer, both jumping to defsrc.
+    fn layer_length_lenses(uri: &Url, masked: &str) -> Vec<CodeLens> {
+        let tokens = Self::tokenize(masked);
+        
+        // Without a defsrc there is nothing to compare layers against
+        let defsrc_open = match Self::find_forms(masked, &tokens, "defsrc").first() {
+            Some(&open) => open,
+            None => return vec![],
+        };
+        let (defsrc_children, _) = Self::form_children(&tokens, defsrc_open);
+        let defsrc_len = defsrc_children.len() - 1;
+        let defsrc_location = Location {
+            uri: uri.clone(),
+            range: Self::form_keyword_range(masked, &tokens, defsrc_open),
+        };
+        
+        let mut lenses = Vec::new();
+        for open in Self::find_forms(masked, &tokens, "deflayer") {
+            // Skip the keyword and the layer name
+            let (children, _) = Self::form_children(&tokens, open);
+            let layer_len = children.len().saturating_sub(2);
+            let range = Self::form_keyword_range(masked, &tokens, open);
+            
+            for title in ["↔ defsrc".to_string(), format!("{}/{} keys", layer_len, defsrc_len)] {
+                lenses.push(CodeLens {
+                    range,
+                    command: Some(Command {
+                        title,
+                        command: "kanata.showDefsrc".to_string(),
+                        arguments: Some(vec![serde_json::json!(defsrc_location)]),
+                    }),
+                    data: None,
+                });
             }
         }
         
-        result.join("\n")
+        lenses
     }
     
-    fn format_deflayer(lines: &[&str], start_idx: usize, layout: &[Vec<usize>]) -> (String, usize) {
-        let mut result = String::new();
-        let first_line = lines[start_idx];
-        let indent = first_line.len() - first_line.trim_start().len();
-        
-        // Extract layer name
-        let trimmed = first_line.trim();
-        let after_deflayer = trimmed.trim_start_matches("(deflayer").trim();
-        let layer_name = after_deflayer
-            .split_whitespace()
-            .next()
-            .unwrap_or("");
-        
-        result.push_str(&" ".repeat(indent));
-        result.push_str("(deflayer ");
-        result.push_str(layer_name);
+    /// The item for the same defsrc key as the one at `offset`, in the next or previous layer.
+    ///
+    /// defsrc and the deflayers are visited in document order, wrapping around, and
+    /// layers too short to have the item are skipped.
+    fn same_key_in_adjacent_layer(masked: &str, offset: usize, forward: bool) -> Option<Range> {
+        let tokens = Self::tokenize(masked);
+        let mut opens: Vec<(usize, usize)> = Self::find_forms(masked, &tokens, "defsrc")
+            .into_iter()
+            .map(|open| (open, 1))
+            .chain(Self::find_forms(masked, &tokens, "deflayer").into_iter().map(|open| (open, 2)))
+            .collect();
+        opens.sort();
+        let layers: Vec<Vec<std::ops::Range<usize>>> = opens
+            .into_iter()
+            .map(|(open, skip)| {
+                let (children, _) = Self::form_children(&tokens, open);
+                children.into_iter().skip(skip).collect()
+            })
+            .collect();
+        let span = |child: &std::ops::Range<usize>| (tokens[child.start].start, tokens[child.end - 1].end);
         
-        // Parse all items in this deflayer
-        let mut items = Vec::new();
-        let mut i = start_idx;
-        let mut in_deflayer = true;
-        let mut paren_depth = 1;
+        let (current, index) = layers.iter().enumerate().find_map(|(layer, items)| {
+            let index = items.iter().position(|item| {
+                let (start, end) = span(item);
+                (start..=end).contains(&offset)
+            })?;
+            Some((layer, index))
+        })?;
         
-        // Skip past layer name on first line
-        let first_line_rest = after_deflayer.trim_start_matches(layer_name).trim();
-        let mut current = String::new();
+        (1..layers.len()).find_map(|step| {
+            let layer = if forward {
+                (current + step) % layers.len()
+            } else {
+                (current + layers.len() - step) % layers.len()
+            };
+            let (start, end) = span(layers[layer].get(index)?);
+            Some(Range {
+                start: Self::offset_to_position(masked, start),
+                end: Self::offset_to_position(masked, end),
+            })
+        })
+    }
+    
+    /// Table of every deflayer's key count against defsrc, and whether its rows line up.
+    fn layout_report(masked: &str) -> String {
+        let tokens = Self::tokenize(masked);
+        let layout = match Self::parse_defsrc_layout(masked, DEFAULT_TAB_SIZE) {
+            Some(layout) => layout,
+            None => return "No defsrc to compare layers against".to_string(),
+        };
+        let defsrc_rows: Vec<usize> = layout.iter().map(Vec::len).collect();
+        let defsrc_len: usize = defsrc_rows.iter().sum();
         
-        for ch in first_line_rest.chars() {
-            if ch == '(' {
-                paren_depth += 1;
-                current.push(ch);
-            } else if ch == ')' {
-                paren_depth -= 1;
-                if paren_depth == 0 {
-                    if !current.trim().is_empty() {
-                        items.push(current.clone());
-                    }
-                    in_deflayer = false;
-                    break;
-                } else {
-                    current.push(ch);
+        let mut rows = vec![[
+            "layer".to_string(),
+            "keys".to_string(),
+            "defsrc".to_string(),
+            "status".to_string(),
+        ]];
+        for open in Self::find_forms(masked, &tokens, "deflayer") {
+            let (children, _) = Self::form_children(&tokens, open);
+            let name = match children.get(1) {
+                Some(child) => {
+                    let (name_start, name_end) = Self::name_span(masked, tokens[child.start]);
+                    &masked[name_start..name_end]
                 }
-            } else if ch.is_whitespace() && paren_depth == 1 {
-                if !current.trim().is_empty() {
-                    items.push(current.clone());
-                    current.clear();
+                None => continue,
+            };
+            
+            // Count the items on each line, as defsrc's rows are counted
+            let mut layer_rows: Vec<usize> = Vec::new();
+            let mut current_line = None;
+            for child in children.iter().skip(2) {
+                let line = Self::offset_to_position(masked, tokens[child.start].start).line;
+                match layer_rows.last_mut() {
+                    Some(count) if current_line == Some(line) => *count += 1,
+                    _ => layer_rows.push(1),
                 }
-            } else {
-                current.push(ch);
+                current_line = Some(line);
             }
+            let layer_len: usize = layer_rows.iter().sum();
+            
+            let status = if layer_len < defsrc_len {
+                format!("{} missing", defsrc_len - layer_len)
+            } else if layer_len > defsrc_len {
+                format!("{} extra", layer_len - defsrc_len)
+            } else if layer_rows != defsrc_rows {
+                "rows differ from defsrc".to_string()
+            } else {
+                "ok".to_string()
+            };
+            rows.push([name.to_string(), layer_len.to_string(), defsrc_len.to_string(), status]);
         }
         
-        if !current.trim().is_empty() && in_deflayer {
-            items.push(current);
+        let widths: Vec<usize> = (0..4)
+            .map(|column| rows.iter().map(|row| row[column].graphemes(true).count()).max().unwrap_or(0))
+            .collect();
+        rows.iter()
+            .map(|row| {
+                let cells: Vec<String> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, &width)| format!("{}{}", cell, " ".repeat(width - cell.graphemes(true).count())))
+                    .collect();
+                cells.join("  ").trim_end().to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    
+    /// Point out that deflayers need a defsrc when neither the document nor its includes have one.
+    async fn missing_defsrc_diagnostics(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        let tokens = Self::tokenize(masked);
+        let first_layer = match Self::find_forms(masked, &tokens, "deflayer").first() {
+            Some(&open) => open,
+            None => return vec![],
+        };
+        if Self::defsrc_source(masked).is_some() {
+            return vec![];
         }
         
-        // Continue parsing subsequent lines
-        i += 1;
-        while i < lines.len() && in_deflayer {
-            let line = lines[i].trim();
-            current = String::new();
-            
-            for ch in line.chars() {
-                if ch == '(' {
-                    paren_depth += 1;
-                    current.push(ch);
-                } else if ch == ')' {
-                    paren_depth -= 1;
-                    if paren_depth == 0 {
-                        if !current.trim().is_empty() {
-                            items.push(current.clone());
-                        }
-                        in_deflayer = false;
-                        break;
-                    } else {
-                        current.push(ch);
-                    }
-                } else if ch.is_whitespace() && paren_depth == 1 {
-                    if !current.trim().is_empty() {
-                        items.push(current.clone());
-                        current.clear();
-                    }
-                } else {
-                    current.push(ch);
-                }
+        let included = self.includes.read().await.get(uri).cloned().unwrap_or_default();
+        for (_, include_uri) in &included {
+            if let Some(text) = self.document_text(include_uri).await
+                && Self::defsrc_source(&Self::mask_comments(&text)).is_some()
+            {
+                return vec![];
+            }
+        }
+        
+        vec![Diagnostic {
+            range: Self::form_keyword_range(masked, &tokens, first_layer),
+            severity: Some(DiagnosticSeverity::INFORMATION),
+            code: DiagnosticCode::MissingDefsrc.code(),
+            code_description: DiagnosticCode::MissingDefsrc.description(),
+            source: Some("kanata-lsp".to_string()),
+            message: "Layers are laid out against a `defsrc`, but none is defined".to_string(),
+            related_information: None,
+            tags: None,
+            data: None,
+        }]
+    }
+    
+    /// Log details that only help when debugging the server.
+    ///
+    /// `detail` is the trace level the message belongs to, `Messages` or `Verbose`. It is
+    /// logged once the client's trace level reaches it; `logging.verbose` logs everything.
+    async fn log_trace(&self, detail: TraceValue, message: String) {
+        let rank = |trace| match trace {
+            TraceValue::Off => 0,
+            TraceValue::Messages => 1,
+            TraceValue::Verbose => 2,
+        };
+        let trace = if self.config.read().await.verbose_logging {
+            TraceValue::Verbose
+        } else {
+            *self.trace.read().await
+        };
+        if rank(trace) >= rank(detail) {
+            self.client.log_message(MessageType::LOG, message).await;
+        }
+    }
+    
+    /// `$/setTrace`: the client changed how much the server should log.
+    async fn set_trace(&self, params: SetTraceParams) {
+        *self.trace.write().await = params.value;
+    }
+    
+    /// Text of an open document, or of a file on disk such as an included one.
+    ///
+    /// Open documents are looked up by URI whatever its scheme, so unsaved `untitled:`
+    /// buffers and remote documents work as well as local files.
+    async fn document_text(&self, uri: &Url) -> Option<String> {
+        if let Some(text) = self.documents.read().await.get(uri) {
+            return Some(text.clone());
+        }
+        tokio::fs::read_to_string(uri.to_file_path().ok()?).await.ok()
+    }
+    
+    /// Alias and layer names referenced anywhere in a document.
+    fn referenced_names(masked: &str) -> (HashSet<String>, HashSet<String>) {
+        let tokens = Self::tokenize(masked);
+        let mut aliases = HashSet::new();
+        let mut layers = HashSet::new();
+        
+        for (idx, token) in tokens.iter().enumerate() {
+            if token.kind != TokenKind::Atom {
+                continue;
+            }
+            let word = &masked[token.start..token.end];
+            if let Some(alias) = word.strip_prefix('@') {
+                aliases.insert(alias.to_string());
             }
             
-            if !current.trim().is_empty() && in_deflayer {
-                items.push(current);
+            // Layer names count in the layer argument of layer forms, as in `references`
+            let is_layer_argument = idx >= 2
+                && tokens[idx - 2].kind == TokenKind::Open
+                && tokens[idx - 1].kind == TokenKind::Atom
+                && LAYER_ACTIONS.contains(&&masked[tokens[idx - 1].start..tokens[idx - 1].end]);
+            if is_layer_argument {
+                let (name_start, name_end) = Self::name_span(masked, *token);
+                layers.insert(masked[name_start..name_end].to_string());
             }
-            i += 1;
         }
         
-        // Only format if item count matches defsrc
-        if items.len() != layout.len() {
-            // Return original lines unchanged
-            let mut original = String::new();
-            for idx in start_idx..i {
-                if idx > start_idx {
-                    original.push('\n');
+        (aliases, layers)
+    }
+    
+    /// Mark aliases and layers of a document that nothing references, in it or any indexed file.
+    ///
+    /// The first deflayer is the starting layer and layers mentioned in defcfg are used
+    /// by the config itself, so those are never reported.
+    async fn unused_symbol_diagnostics(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        let symbols = self.symbols_cache.read().await;
+        let mut used_aliases: HashSet<String> = symbols.values()
+            .flat_map(|doc_symbols| doc_symbols.referenced_aliases.iter().cloned())
+            .collect();
+        let mut used_layers: HashSet<String> = symbols.values()
+            .flat_map(|doc_symbols| doc_symbols.referenced_layers.iter().cloned())
+            .collect();
+        
+        let tokens = Self::tokenize(masked);
+        if let Some(&open) = Self::find_forms(masked, &tokens, "deflayer").first()
+            && let Some(name) = tokens.get(open + 2).filter(|token| token.kind == TokenKind::Atom)
+        {
+            let (name_start, name_end) = Self::name_span(masked, *name);
+            used_layers.insert(masked[name_start..name_end].to_string());
+        }
+        for open in Self::find_forms(masked, &tokens, "defcfg") {
+            let (_, close) = Self::form_children(&tokens, open);
+            for &token in tokens[open..close].iter().filter(|token| token.kind == TokenKind::Atom) {
+                let (name_start, name_end) = Self::name_span(masked, token);
+                used_layers.insert(masked[name_start..name_end].to_string());
+            }
+        }
+        for (kind, name, _) in Self::defcfg_names(masked) {
+            if kind == SymbolKind::Alias {
+                used_aliases.insert(name.to_string());
+            }
+        }
+        
+        let doc_symbols = match symbols.get(uri) {
+            Some(doc_symbols) => doc_symbols,
+            None => return vec![],
+        };
+        
+        let mut diagnostics = Vec::new();
+        for (kind, label, used) in [
+            (SymbolKind::Alias, "Alias", &used_aliases),
+            (SymbolKind::Layer, "Layer", &used_layers),
+        ] {
+            for (name, def) in doc_symbols.definitions(kind) {
+                if used.contains(name) {
+                    continue;
                 }
-                original.push_str(lines[idx]);
+                diagnostics.push(Diagnostic {
+                    range: def.range,
+                    severity: Some(DiagnosticSeverity::HINT),
+                    code: DiagnosticCode::UnusedSymbol.code(),
+                    code_description: DiagnosticCode::UnusedSymbol.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!("{} `{}` is never used", label, name),
+                    related_information: None,
+                    tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                    data: None,
+                });
             }
-            return (original, i);
         }
         
-        // Apply layout
-        for (idx, item) in items.iter().enumerate() {
-            let item_width = item.graphemes(true).count();
-            let target_width = layout[idx][0];
-            
-            result.push('\n');
-            result.push_str(&" ".repeat(indent + 2));
-            result.push_str(item);
+        diagnostics
+    }
+    
+    /// Every reference to a symbol across the indexed documents.
+    async fn symbol_references(&self, kind: SymbolKind, name: &str, include_declaration: bool) -> Vec<Location> {
+        // Snapshot the index so it isn't locked while documents are read
+        let documents: Vec<(Url, Option<Range>)> = self
+            .symbols_cache
+            .read()
+            .await
+            .iter()
+            .map(|(doc_uri, doc_symbols)| (doc_uri.clone(), doc_symbols.definitions(kind).get(name).map(|def| def.range)))
+            .collect();
+        let mut locations = Vec::new();
+        
+        // Search through all indexed documents, using the editor's text for open ones
+        // so unsaved edits count, and reading the rest from disk
+        for (doc_uri, declaration) in &documents {
+            let doc_text = match self.document_text(doc_uri).await {
+                Some(t) => t,
+                None => continue,
+            };
+            // Layer and other names are matched as whole atoms, which never match inside
+            // a string; `@alias` is searched for as text, so strings are masked for it
+            let masked = Self::mask_comments(&doc_text);
             
-            // Add padding if item is shorter than target
-            if item_width < target_width {
-                result.push_str(&" ".repeat(target_width - item_width));
+            if kind == SymbolKind::Alias {
+                // The definition names the alias without its `@`
+                if include_declaration && let Some(range) = declaration {
+                    locations.push(Location {
+                        uri: doc_uri.clone(),
+                        range: *range,
+                    });
+                }
+                
+                let masked = Self::mask_comments_and_strings(&doc_text);
+                for range in Self::alias_reference_ranges(&masked, name) {
+                    locations.push(Location {
+                        uri: doc_uri.clone(),
+                        range,
+                    });
+                }
+            } else if kind == SymbolKind::Layer {
+                // Layer names only count in the layer argument of layer forms,
+                // so a keycode or other text that happens to match is ignored
+                for range in Self::layer_name_ranges(&masked, name, include_declaration) {
+                    locations.push(Location {
+                        uri: doc_uri.clone(),
+                        range,
+                    });
+                }
+            } else {
+                // Other symbols are referenced as the first argument of specific forms,
+                // e.g. sequences through the virtual key actions that trigger them
+                if include_declaration && let Some(range) = declaration {
+                    locations.push(Location {
+                        uri: doc_uri.clone(),
+                        range: *range,
+                    });
+                }
+                
+                for range in Self::form_argument_ranges(&masked, name, kind.reference_forms()) {
+                    locations.push(Location {
+                        uri: doc_uri.clone(),
+                        range,
+                    });
+                }
             }
         }
         
-        result.push('\n');
-        result.push_str(&" ".repeat(indent));
-        result.push(')');
+        locations
+    }
+    
+    /// Ranges where `name` appears as a whole word inside a comment.
+    fn comment_occurrences(text: &str, name: &str) -> Vec<Range> {
+        if name.is_empty() {
+            return vec![];
+        }
         
-        (result, i)
+        // Comments are blanked in the masked text, so a name that is blank there
+        // is commented out; a name in code is left as it is
+        let masked = Self::mask_comments(text);
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        text.match_indices(name)
+            .filter(|&(start, _)| {
+                let end = start + name.len();
+                masked[start..end].chars().all(char::is_whitespace)
+                    && !text[..start].ends_with(is_name_char)
+                    && !text[end..].starts_with(is_name_char)
+            })
+            .map(|(start, _)| Range {
+                start: Self::offset_to_position(text, start),
+                end: Self::offset_to_position(text, start + name.len()),
+            })
+            .collect()
     }
     
-    fn get_word_at_position(text: &str, position: Position) -> String {
-        let lines: Vec<&str> = text.lines().collect();
-        if position.line as usize >= lines.len() {
-            return String::new();
+    /// Sort one file's edits and drop repeats, since clients reject overlapping edits.
+    ///
+    /// Reference ranges never overlap unless they are the same range found twice, so
+    /// an edit overlapping the previous one is dropped too rather than sent.
+    fn disjoint_edits(edits: &mut Vec<TextEdit>) {
+        edits.sort_by_key(|edit| (edit.range.start, edit.range.end));
+        edits.dedup_by(|edit, previous| edit.range.start < previous.range.end || edit.range == previous.range);
+        debug_assert!(edits.windows(2).all(|pair| pair[0].range.end <= pair[1].range.start));
+    }
+    
+    /// Like `classify_word`, but a word on the name of a definition is that symbol,
+    /// which matters for alias definitions since they are written without the `@`.
+    fn classify_word_at<'a>(
+        uri: &Url,
+        position: Position,
+        word: &'a str,
+        symbols: &HashMap<Url, DocumentSymbols>,
+    ) -> (SymbolKind, &'a str) {
+        if let Some(doc_symbols) = symbols.get(uri) {
+            for kind in [
+                SymbolKind::Alias,
+                SymbolKind::Layer,
+                SymbolKind::VirtualKey,
+                SymbolKind::Sequence,
+                SymbolKind::Template,
+            ] {
+                let on_definition = doc_symbols.definitions(kind).get(word).is_some_and(|def| {
+                    def.uri == *uri && def.range.start <= position && position <= def.range.end
+                });
+                if on_definition {
+                    return (kind, word);
+                }
+            }
+        }
+        Self::classify_word(word, symbols)
+    }
+    
+    /// `@name` is always an alias; other words are layers unless only a
+    /// virtual key, sequence or template of that name exists.
+    fn classify_word<'a>(word: &'a str, symbols: &HashMap<Url, DocumentSymbols>) -> (SymbolKind, &'a str) {
+        if let Some(alias_name) = word.strip_prefix('@') {
+            return (SymbolKind::Alias, alias_name);
         }
         
-        let line = lines[position.line as usize];
-        let char_pos = position.character as usize;
+        let is_defined = |kind| symbols.values().any(|doc_symbols| doc_symbols.definitions(kind).contains_key(word));
+        let kind = [SymbolKind::Layer, SymbolKind::VirtualKey, SymbolKind::Sequence, SymbolKind::Template]
+            .into_iter()
+            .find(|&kind| is_defined(kind))
+            .unwrap_or(SymbolKind::Layer);
+        (kind, word)
+    }
+
+    /// Align every deflayer in `text` to `defsrc_layout` and apply the spacing options.
+    ///
+    /// The layout is parsed from the whole file up front, so layers written before
+    /// `defsrc` are aligned just like those after it.
+    fn format_document(text: &str, defsrc_layout: &[Vec<usize>], options: &FormatOptions) -> String {
+        // Commented-out forms must not take part in formatting
+        let masked = Self::mask_comments(text);
         
-        if char_pos >= line.len() {
-            return String::new();
+        // Apply layout to all deflayers
+        let mut formatted = Self::apply_defsrc_layout_to_deflayers(text, &masked, defsrc_layout, options);
+        if options.normalize_spacing {
+            formatted = Self::normalize_form_spacing(&formatted);
         }
+        let mut formatted = Self::normalize_blank_lines(&formatted, options.blank_lines_between_forms);
         
-        // Find the start of the word (including @ for aliases)
-        let mut start = char_pos;
-        while start > 0 {
-            let c = line.chars().nth(start - 1).unwrap();
-            if c.is_alphanumeric() || c == '_' || c == '-' || c == '@' {
-                start -= 1;
-            } else {
-                break;
-            }
+        // Splitting into lines drops the final newline, so put it back
+        if text.ends_with('\n') {
+            formatted.push('\n');
         }
         
-        // Find the end of the word
-        let mut end = char_pos;
-        while end < line.len() {
-            let c = line.chars().nth(end).unwrap();
-            if c.is_alphanumeric() || c == '_' || c == '-' {
-                end += 1;
-            } else {
-                break;
-            }
+        // Lines are rejoined with `\n`, so restore CRLF endings for Windows files
+        if text.contains("\r\n") {
+            formatted.replace('\n', "\r\n")
+        } else {
+            formatted
         }
-        
-        line[start..end].to_string()
     }
     
-    fn extract_symbols(uri: &Url, text: &str) -> DocumentSymbols {
-        let mut aliases = HashMap::new();
-        let mut layers = HashMap::new();
+    /// Collapse runs of spaces and tabs between items to one space, except in defsrc and layers.
+    ///
+    /// Indentation, line breaks, and whitespace inside strings or comments or next to a
+    /// comment are kept, and no whitespace is added or removed entirely, so the structure
+    /// can't change.
+    fn normalize_form_spacing(text: &str) -> String {
+        let masked = Self::mask_comments_and_strings(text);
+        let tokens = Self::tokenize(&masked);
+        let bytes = text.as_bytes();
+        let is_code = |idx: usize| !bytes[idx].is_ascii_whitespace() && masked.as_bytes()[idx] == bytes[idx];
         
-        // Convert text to bytes with line/column tracking
-        let lines: Vec<&str> = text.lines().collect();
-        
-        for (line_idx, line) in lines.iter().enumerate() {
-            let trimmed = line.trim_start();
-            let indent = line.len() - trimmed.len();
-            
-            // Check if this line starts with (defalias or (deflayer
-            if trimmed.starts_with("(defalias") {
-                // Check if the name is on the same line
-                let after_keyword = trimmed.trim_start_matches("(defalias").trim_start();
-                if !after_keyword.is_empty() && !after_keyword.starts_with('(') && !after_keyword.starts_with(')') {
-                    // Name is on the same line
-                    let name_end = after_keyword.find(|c: char| c.is_whitespace() || c == '(' || c == ')')
-                        .unwrap_or(after_keyword.len());
-                    let alias_name = &after_keyword[..name_end];
-                    let name_col = indent + "(defalias".len() + (after_keyword.as_ptr() as usize - trimmed.trim_start_matches("(defalias").as_ptr() as usize);
-                    
-                    aliases.insert(alias_name.to_string(), Definition {
-                        uri: uri.clone(),
-                        range: Range {
-                            start: Position {
-                                line: line_idx as u32,
-                                character: name_col as u32,
-                            },
-                            end: Position {
-                                line: line_idx as u32,
-                                character: (name_col + alias_name.len()) as u32,
-                            },
-                        },
-                    });
-                } else {
-                    // Name might be on the next line(s)
-                    for (offset, next_line) in lines[(line_idx + 1)..].iter().enumerate() {
-                        let next_trimmed = next_line.trim_start();
-                        let next_indent = next_line.len() - next_trimmed.len();
-                        
-                        if next_trimmed.is_empty() || next_trimmed.starts_with(';') {
-                            continue;
-                        }
-                        
-                        if next_trimmed.starts_with(')') {
-                            break;
-                        }
-                        
-                        // This should be the alias name
-                        let name_end = next_trimmed.find(|c: char| c.is_whitespace() || c == '(' || c == ')')
-                            .unwrap_or(next_trimmed.len());
-                        let alias_name = &next_trimmed[..name_end];
-                        
-                        if !alias_name.is_empty() {
-                            let actual_line = line_idx + 1 + offset;
-                            
-                            aliases.insert(alias_name.to_string(), Definition {
-                                uri: uri.clone(),
-                                range: Range {
-                                    start: Position {
-                                        line: actual_line as u32,
-                                        character: next_indent as u32,
-                                    },
-                                    end: Position {
-                                        line: actual_line as u32,
-                                        character: (next_indent + alias_name.len()) as u32,
-                                    },
-                                },
-                            });
-                            break;
-                        }
-                    }
-                }
-            } else if trimmed.starts_with("(deflayer") {
-                // Check if the name is on the same line
-                let after_keyword = trimmed.trim_start_matches("(deflayer").trim_start();
-                if !after_keyword.is_empty() && !after_keyword.starts_with('(') && !after_keyword.starts_with(')') {
-                    // Name is on the same line
-                    let name_end = after_keyword.find(|c: char| c.is_whitespace() || c == '(' || c == ')')
-                        .unwrap_or(after_keyword.len());
-                    let layer_name = &after_keyword[..name_end];
-                    let name_col = indent + "(deflayer".len() + (after_keyword.as_ptr() as usize - trimmed.trim_start_matches("(deflayer").as_ptr() as usize);
-                    
-                    layers.insert(layer_name.to_string(), Definition {
-                        uri: uri.clone(),
-                        range: Range {
-                            start: Position {
-                                line: line_idx as u32,
-                                character: name_col as u32,
-                            },
-                            end: Position {
-                                line: line_idx as u32,
-                                character: (name_col + layer_name.len()) as u32,
-                            },
-                        },
-                    });
-                } else {
-                    // Name might be on the next line(s)
-                    for (offset, next_line) in lines[(line_idx + 1)..].iter().enumerate() {
-                        let next_trimmed = next_line.trim_start();
-                        let next_indent = next_line.len() - next_trimmed.len();
-                        
-                        if next_trimmed.is_empty() || next_trimmed.starts_with(';') {
-                            continue;
-                        }
-                        
-                        if next_trimmed.starts_with(')') {
-                            break;
-                        }
-                        
-                        // This should be the layer name
-                        let name_end = next_trimmed.find(|c: char| c.is_whitespace() || c == '(' || c == ')')
-                            .unwrap_or(next_trimmed.len());
-                        let layer_name = &next_trimmed[..name_end];
-                        
-                        if !layer_name.is_empty() {
-                            let actual_line = line_idx + 1 + offset;
-                            
-                            layers.insert(layer_name.to_string(), Definition {
-                                uri: uri.clone(),
-                                range: Range {
-                                    start: Position {
-                                        line: actual_line as u32,
-                                        character: next_indent as u32,
-                                    },
-                                    end: Position {
-                                        line: actual_line as u32,
-                                        character: (next_indent + layer_name.len()) as u32,
-                                    },
-                                },
-                            });
-                            break;
+        // Byte ranges of the top-level forms whose spacing is free to change
+        let mut regions = Vec::new();
+        let mut depth = 0;
+        for (idx, token) in tokens.iter().enumerate() {
+            match token.kind {
+                TokenKind::Open => {
+                    if depth == 0 {
+                        let keyword = tokens
+                            .get(idx + 1)
+                            .filter(|next| next.kind == TokenKind::Atom)
+                            .map(|next| &masked[next.start..next.end]);
+                        if !matches!(keyword, Some("defsrc" | "deflayer" | "deflayermap")) {
+                            let (_, close) = Self::form_children(&tokens, idx);
+                            let end = tokens.get(close).map_or(text.len(), |close| close.end);
+                            regions.push(token.start..end);
                         }
                     }
+                    depth += 1;
                 }
+                TokenKind::Close => depth -= 1,
+                TokenKind::Atom => {}
             }
         }
         
-        DocumentSymbols { aliases, layers }
+        let mut result = String::with_capacity(text.len());
+        let mut copied = 0;
+        for region in regions {
+            let mut idx = region.start;
+            while idx < region.end {
+                if bytes[idx] != b' ' && bytes[idx] != b'\t' {
+                    idx += 1;
+                    continue;
+                }
+                let run_end = idx + text[idx..].find(|c: char| c != ' ' && c != '\t').unwrap_or(text.len() - idx);
+                // Only between two pieces of code on the same line
+                let collapsible = run_end - idx > 1 || bytes[idx] == b'\t';
+                if collapsible && is_code(idx - 1) && run_end < region.end && is_code(run_end) {
+                    result.push_str(&text[copied..idx]);
+                    result.push(' ');
+                    copied = run_end;
+                }
+                idx = run_end;
+            }
+        }
+        result.push_str(&text[copied..]);
+        result
     }
     
-    fn extract_line_info(error_msg: &str) -> (u32, u32, u32) {
-        // Try to extract line number from the visual range markers first
-        // Format: "79 │ ╭─▶" to "85 │ ├─▶"
-        let mut start_line = None;
-        let mut end_line = None;
+    /// Collapse or expand each run of blank lines between top-level forms to `count` lines.
+    ///
+    /// Blank lines inside forms, before the first form and after the last are left alone.
+    fn normalize_blank_lines(text: &str, count: usize) -> String {
+        let masked = Self::mask_comments(text);
+        let mut result: Vec<&str> = Vec::new();
+        let mut pending_blanks: Vec<&str> = Vec::new();
+        let mut depth = 0i32;
         
-        for line in error_msg.lines() {
-            // Look for the start marker
-            if (line.contains("╭─▶") || line.contains("│ ╭─▶")) && start_line.is_none() {
-                if let Some(num_str) = line.split('│').next() {
-                    if let Ok(num) = num_str.trim().parse::<u32>() {
-                        start_line = Some(num.saturating_sub(1)); // Convert to 0-based
-                    }
+        for (line, masked_line) in text.lines().zip(masked.lines()) {
+            if line.trim().is_empty() {
+                pending_blanks.push(line);
+                continue;
+            }
+            
+            if !pending_blanks.is_empty() {
+                if depth == 0 && !result.is_empty() {
+                    result.extend(std::iter::repeat_n("", count));
+                } else {
+                    result.append(&mut pending_blanks);
                 }
+                pending_blanks.clear();
             }
-            // Look for the end marker
-            if line.contains("├─▶") || line.contains("╰──") {
-                if let Some(num_str) = line.split('│').next() {
-                    if let Ok(num) = num_str.trim().parse::<u32>() {
-                        end_line = Some(num.saturating_sub(1)); // Convert to 0-based
-                    }
+            
+            result.push(line);
+            for ch in masked_line.chars() {
+                match ch {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
                 }
             }
         }
         
-        // If we found both start and end markers, use them
-        if let (Some(start), Some(end)) = (start_line, end_line) {
-            return (start, 0, end);
+        result.append(&mut pending_blanks);
+        result.join("\n")
+    }
+    
+    /// The defsrc layout of a document, reusing the cached one while defsrc and the tab
+    /// width are unchanged.
+    async fn defsrc_layout(&self, uri: &Url, text: &str, tab_size: usize) -> Option<Vec<Vec<usize>>> {
+        if let Some(cached) = self.defsrc_cache.read().await.get(uri)
+            && cached.tab_size == tab_size
+        {
+            return cached.layout.clone();
         }
         
-        // Fallback: Look for line:col in brackets like [file.kbd:78:1]
-        if let Some(start) = error_msg.find(".kbd:") {
-            let after_kbd = &error_msg[start + 5..];
-            if let Some(end) = after_kbd.find(']') {
-                let coords = &after_kbd[..end];
-                let parts: Vec<&str> = coords.split(':').collect();
-                
-                if parts.len() >= 2 {
-                    if let (Ok(line), Ok(col)) = (parts[0].parse::<u32>(), parts[1].parse::<u32>()) {
-                        let line_0 = line.saturating_sub(1);
-                        let col_0 = col.saturating_sub(1);
-                        return (line_0, col_0, line_0);
-                    }
-                }
+        let masked = Self::mask_comments(text);
+        let layout = Self::parse_defsrc_layout(&masked, tab_size);
+        self.defsrc_cache.write().await.insert(uri.clone(), DefsrcCache {
+            source: Self::defsrc_source(&masked).unwrap_or_default().to_string(),
+            tab_size,
+            layout: layout.clone(),
+        });
+        
+        layout
+    }
+    
+    /// Drop the cached defsrc layout if the defsrc form in `text` no longer matches it.
+    async fn invalidate_defsrc_layout(&self, uri: &Url, text: &str) {
+        let mut cache = self.defsrc_cache.write().await;
+        if let Some(cached) = cache.get(uri) {
+            let masked = Self::mask_comments(text);
+            if Self::defsrc_source(&masked).unwrap_or_default() != cached.source {
+                cache.remove(uri);
             }
         }
+    }
+    
+    /// Source text of the `(defsrc ...)` form, from its opening to its closing paren.
+    fn defsrc_source(masked: &str) -> Option<&str> {
+        let tokens = Self::tokenize(masked);
+        let open = *Self::find_forms(masked, &tokens, "defsrc").first()?;
         
-        // Default to line 0 if we can't parse
-        (0, 0, 0)
+        let (_, close) = Self::form_children(&tokens, open);
+        let end = tokens.get(close).map(|token| token.end).unwrap_or(masked.len());
+        Some(&masked[tokens[open].start..end])
     }
-
-    async fn validate_document(&self, uri: &Url, text: &str) {
-        // Extract symbols from the document
-        let symbols = Self::extract_symbols(uri, text);
-        self.symbols_cache.write().await.insert(uri.clone(), symbols);
+    
+    /// Column widths of the defsrc items, grouped by the source line they appear on.
+    ///
+    /// An item is as wide as its graphemes, but one followed by a tab on its line spans to
+    /// the next item's tab stop, so layers keep the columns a tab-separated defsrc shows.
+    fn parse_defsrc_layout(text: &str, tab_size: usize) -> Option<Vec<Vec<usize>>> {
+        let tokens = Self::tokenize(text);
+        let open = *Self::find_forms(text, &tokens, "defsrc").first()?;
         
-        // Write text to a temporary file to parse it
-        let temp_file = std::env::temp_dir().join("kanata-temp.kbd");
-        let diagnostics = match std::fs::write(&temp_file, text) {
-            Ok(_) => {
-                // Parse the file and immediately convert to error string
-                let parse_result = kanata_parser::cfg::new_from_file(&temp_file)
-                    .map(|_| ())
-                    .map_err(|e| format!("{:?}", e));
-                
-                match parse_result {
-                    Ok(_) => {
-                        // Parsing succeeded, no diagnostics
-                        vec![]
-                    }
-                    Err(error_msg) => {
-                        // Parse error - create diagnostic
-                        
-                        // Extract line information from error message
-                        let (start_line, start_col, end_line) = Self::extract_line_info(&error_msg);
-                        
-                        // Get the actual line length to avoid going past end of line
-                        let end_col = if start_line == end_line {
-                            // Single line diagnostic - highlight from start_col to end of line
-                            let line_len = text.lines().nth(start_line as usize)
-                                .map(|line| line.len() as u32)
-                                .unwrap_or(start_col + 1);
-                            // Ensure end_col is at least 1 character after start_col
-                            line_len.max(start_col + 1)
-                        } else {
-                            // Multi-line diagnostic - highlight to end of end_line
-                            text.lines().nth(end_line as usize)
-                                .map(|line| line.len() as u32)
-                                .unwrap_or(0)
-                        };
-                        
-                        // Log the error message for debugging
-                        self.client.log_message(
-                            MessageType::INFO,
-                            format!("Full error: {}", error_msg.lines().take(15).collect::<Vec<_>>().join(" || "))
-                        ).await;
-                        
-                        // Extract just the text after "help:"
-                        let display_message = error_msg.lines()
-                            .find(|line| line.contains("help:"))
-                            .and_then(|line| line.split("help:").nth(1))
-                            .map(|s| s.trim().to_string())
-                            .unwrap_or_else(|| "Parse error".to_string());
-                        
-                        self.client.log_message(
-                            MessageType::INFO,
-                            format!("Extracted message: {}", display_message)
-                        ).await;
-                        
-                        // Ensure the range is valid
-                        let (final_start_line, final_start_col, final_end_line, final_end_col) = 
-                            if start_line > end_line || (start_line == end_line && start_col >= end_col) {
-                                // Invalid range, use a minimal valid range at start position
-                                (start_line, start_col, start_line, start_col + 1)
-                            } else {
-                                (start_line, start_col, end_line, end_col)
-                            };
-                        
-                        self.client.log_message(
-                            MessageType::INFO,
-                            format!("Diagnostic range: {}:{} to {}:{}", 
-                                final_start_line, final_start_col, final_end_line, final_end_col)
-                        ).await;
-                        
-                        vec![Diagnostic {
-                            range: Range {
-                                start: Position {
-                                    line: final_start_line,
-                                    character: final_start_col,
-                                },
-                                end: Position {
-                                    line: final_end_line,
-                                    character: final_end_col,
-                                },
-                            },
-                            severity: Some(DiagnosticSeverity::ERROR),
-                            code: None,
-                            code_description: None,
-                            source: Some("kanata-lsp".to_string()),
-                            message: display_message,
-                            related_information: None,
-                            tags: None,
-                            data: None,
-                        }]
-                    }
+        let (children, _) = Self::form_children(&tokens, open);
+        let mut layout: Vec<Vec<usize>> = Vec::new();
+        let mut current_line = None;
+        
+        for (idx, child) in children.iter().enumerate().skip(1) {
+            let start = tokens[child.start].start;
+            let end = tokens[child.end - 1].end;
+            let line = Self::offset_to_position(text, start).line;
+            let mut width = text[start..end].graphemes(true).count();
+            
+            if let Some(next) = children.get(idx + 1) {
+                let next_start = tokens[next.start].start;
+                let gap = &text[end..next_start];
+                if gap.contains('\t') && !gap.contains('\n') {
+                    let line_start = text[..start].rfind('\n').map_or(0, |pos| pos + 1);
+                    let column = Self::display_width(&text[line_start..start], 0, tab_size);
+                    // Less the single space the formatter puts between items
+                    width = width.max(Self::display_width(&text[start..next_start], column, tab_size) - 1);
                 }
             }
-            Err(e) => {
-                vec![Diagnostic {
-                    range: Range {
-                        start: Position { line: 0, character: 0 },
-                        end: Position { line: 0, character: 0 },
-                    },
-                    severity: Some(DiagnosticSeverity::ERROR),
-                    code: None,
-                    code_description: None,
-                    source: Some("kanata-lsp".to_string()),
-                    message: format!("Failed to write temp file: {}", e),
-                    related_information: None,
-                    tags: None,
-                    data: None,
-                }]
+            
+            // Start a new row whenever defsrc moves on to a new line
+            match layout.last_mut() {
+                Some(row) if current_line == Some(line) => row.push(width),
+                _ => layout.push(vec![width]),
             }
-        };
-
-        // Store diagnostics in cache for pull diagnostics
-        self.diagnostics_cache.write().await.insert(uri.clone(), diagnostics.clone());
-
-        // Also publish diagnostics for push model
-        self.client
-            .publish_diagnostics(uri.clone(), diagnostics, None)
-            .await;
+            current_line = Some(line);
+        }
+        
+        if layout.is_empty() {
+            return None;
+        }
+        
+        Some(layout)
     }
-}
-
-#[tokio::main]
-async fn main() {
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
-
-    let (service, socket) = LspService::new(|client| KanataLanguageServer { 
-        client,
-        diagnostics_cache: Arc::new(RwLock::new(HashMap::new())),
-        symbols_cache: Arc::new(RwLock::new(HashMap::new())),
-    });
     
-    Server::new(stdin, stdout, socket).serve(service).await;
+    /// Columns `text` takes when it starts at display column `column`, each tab running
+    /// to the next multiple of `tab_size`.
+    fn display_width(text: &str, column: usize, tab_size: usize) -> usize {
+        let tab_size = tab_size.max(1);
+        let end = text.graphemes(true).fold(column, |col, grapheme| {
+            if grapheme == "\t" { col + tab_size - col % tab_size } else { col + 1 }
+        });
+        end - column
+    }
+    
+    fn apply_defsrc_layout_to_deflayers(
+        text: &str,
+        masked: &str,
+        layout: &[Vec<usize>],
+        options: &FormatOptions,
+    ) -> String {
+        let lines: Vec<&str> = text.lines().collect();
+        let mut result = Vec::new();
+        let mut i = 0;
+        
+        for (start, end, formatted) in Self::formatted_deflayers(text, masked, layout, options) {
+            result.extend(lines[i..start].iter().map(|line| line.to_string()));
+            result.push(formatted);
+            i = end;
+        }
+        result.extend(lines[i..].iter().map(|line| line.to_string()));
+        
+        result.join("\n")
+    }
+    
+    /// Every deflayer the formatter rewrites: its first line, the line after its last,
+    /// and its formatted text.
+    ///
+    /// Reformatting would drop comments inside a layer, so such layers are left out, unless
+    /// `align_trailing_comments` is on and they can be put back (see `align_trailing_comments`).
+    fn formatted_deflayers(
+        text: &str,
+        masked: &str,
+        layout: &[Vec<usize>],
+        options: &FormatOptions,
+    ) -> Vec<(usize, usize, String)> {
+        let lines: Vec<&str> = text.lines().collect();
+        let masked_lines: Vec<&str> = masked.lines().collect();
+        let mut layers = Vec::new();
+        let mut i = 0;
+        
+        while i < lines.len() {
+            if masked_lines[i].trim().starts_with("(deflayer") {
+                let (formatted, end) = Self::format_deflayer(&masked_lines, i, layout, options);
+                if lines[i..end] == masked_lines[i..end] {
+                    layers.push((i, end, formatted));
+                } else if options.align_trailing_comments
+                    && let Some(formatted) =
+                        Self::align_trailing_comments(&lines[i..end], &masked_lines[i..end], &formatted)
+                {
+                    layers.push((i, end, formatted));
+                }
+                i = end;
+            } else {
+                i += 1;
+            }
+        }
+        
+        layers
+    }
+    
+    /// Put a layer's trailing `;;` comments back onto its formatted lines, starting them
+    /// all in one column two spaces past the widest row of keys.
+    ///
+    /// A comment on the `(deflayer` line or the closing paren's line goes in the same
+    /// column, or two spaces after that line's text if it is wider than the rows.
+    ///
+    /// Only works when every line keeps the same items and all comments come after the
+    /// items on their line; otherwise `None`, and the layer is left as it is.
+    fn align_trailing_comments(lines: &[&str], masked_lines: &[&str], formatted: &str) -> Option<String> {
+        // A formatted layer has its own lines for the keyword, each row and the closing paren
+        let formatted_lines: Vec<&str> = formatted.lines().collect();
+        if formatted_lines.len() != lines.len() || lines.len() < 3 {
+            return None;
+        }
+        
+        let mut rows = Vec::new();
+        for ((line, masked_line), formatted_line) in lines.iter().zip(masked_lines).zip(&formatted_lines) {
+            if !masked_line.split_whitespace().eq(formatted_line.split_whitespace()) {
+                return None;
+            }
+            let code_end = masked_line.trim_end().len();
+            let comment = line[code_end..].trim_start();
+            if line[..code_end] != masked_line[..code_end] || !(comment.is_empty() || comment.starts_with(";;")) {
+                return None;
+            }
+            rows.push((*formatted_line, comment));
+        }
+        
+        let width = |row: &str| row.graphemes(true).count();
+        let column = rows[1..rows.len() - 1].iter().map(|(row, _)| width(row)).max().unwrap_or(0) + 2;
+        let aligned: Vec<String> = rows
+            .into_iter()
+            .map(|(row, comment)| {
+                if comment.is_empty() {
+                    row.to_string()
+                } else {
+                    let padding = column.saturating_sub(width(row)).max(2);
+                    format!("{}{}{}", row, " ".repeat(padding), comment)
+                }
+            })
+            .collect();
+        Some(aligned.join("\n"))
+    }
+    
+    /// One edit per deflayer touching `range` that formatting would change.
+    ///
+    /// Unlike whole-document formatting, everything between the layers, defsrc, comments
+    /// and blank lines included, is left exactly as it is.
+    fn range_format_edits(text: &str, layout: &[Vec<usize>], options: &FormatOptions, range: Range) -> Vec<TextEdit> {
+        let masked = Self::mask_comments(text);
+        let lines: Vec<&str> = text.lines().collect();
+        let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        
+        Self::formatted_deflayers(text, &masked, layout, options)
+            .into_iter()
+            .filter(|&(start, end, _)| start as u32 <= range.end.line && range.start.line < end as u32)
+            .filter(|(start, end, formatted)| lines[*start..*end].join("\n") != *formatted)
+            .map(|(start, end, formatted)| TextEdit {
+                range: Range {
+                    start: Position::new(start as u32, 0),
+                    end: Position::new(end as u32 - 1, lines[end - 1].encode_utf16().count() as u32),
+                },
+                new_text: formatted.replace('\n', line_ending),
+            })
+            .collect()
+    }
+    
+    /// Add the top-level items on one line of a deflayer to `items`, tracking paren depth
+    /// across lines. Returns the index of the paren closing the layer if it is on this line.
+    ///
+    /// A string belongs to the item it is in, so parens and spaces inside it, like those
+    /// of a `(cmd "...")` command, neither change the depth nor split the item.
+    fn split_layer_line(line: &str, paren_depth: &mut i32, items: &mut Vec<String>) -> Option<usize> {
+        let mut current = String::new();
+        let mut string_end = 0;
+        
+        for (idx, ch) in line.char_indices() {
+            if idx < string_end {
+                current.push(ch);
+            } else if let Some((open, close)) = Self::string_delimiters(&line.as_bytes()[idx..])
+                && let Some(pos) = line[idx + open.len()..].find(close)
+            {
+                string_end = idx + open.len() + pos + close.len();
+                current.push(ch);
+            } else if ch == '(' {
+                *paren_depth += 1;
+                current.push(ch);
+            } else if ch == ')' {
+                *paren_depth -= 1;
+                if *paren_depth == 0 {
+                    if !current.trim().is_empty() {
+                        items.push(current);
+                    }
+                    return Some(idx);
+                }
+                current.push(ch);
+            } else if ch.is_whitespace() && *paren_depth == 1 {
+                if !current.trim().is_empty() {
+                    items.push(std::mem::take(&mut current));
+                }
+            } else {
+                current.push(ch);
+            }
+        }
+        
+        if !current.trim().is_empty() {
+            items.push(current);
+        }
+        None
+    }
+    
+    fn format_deflayer(
+        lines: &[&str],
+        start_idx: usize,
+        layout: &[Vec<usize>],
+        options: &FormatOptions,
+    ) -> (String, usize) {
+        let mut result = String::new();
+        let first_line = lines[start_idx];
+        // Reuse the line's own indentation so tab-indented layers stay aligned
+        // whatever width the editor gives a tab
+        let indent = &first_line[..first_line.len() - first_line.trim_start().len()];
+        
+        // Extract layer name, a quoted one spaces and all
+        let trimmed = first_line.trim();
+        let after_deflayer = trimmed.trim_start_matches("(deflayer").trim();
+        let name_end = Self::tokenize(after_deflayer)
+            .first()
+            .filter(|token| token.kind == TokenKind::Atom)
+            .map_or(0, |token| token.end);
+        let layer_name = &after_deflayer[..name_end];
+        
+        result.push_str(indent);
+        result.push_str("(deflayer ");
+        result.push_str(layer_name);
+        
+        // Parse all items in this deflayer
+        let mut items = Vec::new();
+        let mut i = start_idx;
+        let mut in_deflayer = true;
+        let mut paren_depth = 1;
+        
+        // Skip past layer name on first line
+        let first_line_rest = after_deflayer[name_end..].trim();
+        // Anything after the closing paren, like another form, can't be kept when reformatting
+        let mut trailing_content = false;
+        
+        if let Some(close) = Self::split_layer_line(first_line_rest, &mut paren_depth, &mut items) {
+            trailing_content = !first_line_rest[close + 1..].trim().is_empty();
+            in_deflayer = false;
+        }
+        
+        // Continue parsing subsequent lines
+        i += 1;
+        while i < lines.len() && in_deflayer {
+            let line = lines[i].trim();
+            if let Some(close) = Self::split_layer_line(line, &mut paren_depth, &mut items) {
+                trailing_content = !line[close + 1..].trim().is_empty();
+                in_deflayer = false;
+            }
+            i += 1;
+        }
+        
+        // Only format if item count matches defsrc
+        let defsrc_len: usize = layout.iter().map(Vec::len).sum();
+        if items.len() != defsrc_len || trailing_content {
+            // Return original lines unchanged
+            let mut original = String::new();
+            for idx in start_idx..i {
+                if idx > start_idx {
+                    original.push('\n');
+                }
+                original.push_str(lines[idx]);
+            }
+            return (original, i);
+        }
+        
+        // Apply layout, breaking lines wherever defsrc does
+        let mut items = items.iter();
+        for row in layout {
+            let mut row_text = String::new();
+            
+            for (col, &target_width) in row.iter().enumerate() {
+                let item = items.next().map(String::as_str).unwrap_or("");
+                let item_width = item.graphemes(true).count();
+                
+                if col > 0 {
+                    row_text.push(' ');
+                }
+                
+                // Add padding if item is shorter than target
+                let padding = target_width.saturating_sub(item_width);
+                let (before, after) = match options.column_align {
+                    ColumnAlign::Left => (0, padding),
+                    ColumnAlign::Right => (padding, 0),
+                    ColumnAlign::Center => (padding / 2, padding - padding / 2),
+                };
+                row_text.push_str(&" ".repeat(before));
+                row_text.push_str(item);
+                row_text.push_str(&" ".repeat(after));
+            }
+            
+            result.push('\n');
+            result.push_str(indent);
+            result.push_str("  ");
+            result.push_str(row_text.trim_end());
+        }
+        
+        result.push('\n');
+        result.push_str(indent);
+        result.push(')');
+        
+        (result, i)
+    }
+    
+    fn keycode_completions() -> Vec<CompletionItem> {
+        let mut items = Vec::new();
+        
+        for keycode in KEYCODES {
+            items.push(CompletionItem {
+                label: keycode.name.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                detail: Some("keycode".to_string()),
+                documentation: Some(Documentation::String(keycode.description.to_string())),
+                ..CompletionItem::default()
+            });
+            
+            // Alternative spellings point back at the canonical name
+            for alias in keycode.aliases {
+                items.push(CompletionItem {
+                    label: alias.to_string(),
+                    kind: Some(CompletionItemKind::KEYWORD),
+                    detail: Some(format!("alias of `{}`", keycode.name)),
+                    ..CompletionItem::default()
+                });
+            }
+        }
+        
+        items
+    }
+    
+    /// Keys a `deflocalkeys-*` form defines, as (form keyword, key name, OS key code).
+    ///
+    /// The keyword carries the platform the key applies to, e.g. `deflocalkeys-linux`.
+    fn local_keys(masked: &str) -> Vec<(&str, &str, &str)> {
+        let tokens = Self::tokenize(masked);
+        let mut keys = Vec::new();
+        
+        for (open, pair) in tokens.windows(2).enumerate() {
+            let keyword = &masked[pair[1].start..pair[1].end];
+            if pair[0].kind != TokenKind::Open
+                || pair[1].kind != TokenKind::Atom
+                || !keyword.starts_with("deflocalkeys-")
+            {
+                continue;
+            }
+            
+            let (children, _) = Self::form_children(&tokens, open);
+            for pair in children[1..].chunks_exact(2) {
+                let (name, code) = (tokens[pair[0].start], tokens[pair[1].start]);
+                if pair[0].len() == 1 && name.kind == TokenKind::Atom && code.kind == TokenKind::Atom {
+                    keys.push((keyword, &masked[name.start..name.end], &masked[code.start..code.end]));
+                }
+            }
+        }
+        
+        keys
+    }
+    
+    /// Completions for the keys `deflocalkeys-*` forms define, one per name.
+    fn local_key_completions(masked: &str) -> Vec<CompletionItem> {
+        let mut forms_by_name: Vec<(&str, Vec<&str>)> = Vec::new();
+        for (keyword, name, _) in Self::local_keys(masked) {
+            match forms_by_name.iter_mut().find(|(existing, _)| *existing == name) {
+                Some((_, forms)) => forms.push(keyword),
+                None => forms_by_name.push((name, vec![keyword])),
+            }
+        }
+        
+        forms_by_name
+            .into_iter()
+            .map(|(name, forms)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                detail: Some(format!("local key ({})", forms.join(", "))),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    /// Split comment-masked text into parens and atoms.
+    fn tokenize(masked: &str) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut atom_start = None;
+        let mut string_end = 0;
+        
+        for (idx, ch) in masked.char_indices() {
+            if idx < string_end {
+                continue;
+            }
+            if ch.is_whitespace() || ch == '(' || ch == ')' {
+                if let Some(start) = atom_start.take() {
+                    tokens.push(Token { kind: TokenKind::Atom, start, end: idx });
+                }
+                match ch {
+                    '(' => tokens.push(Token { kind: TokenKind::Open, start: idx, end: idx + 1 }),
+                    ')' => tokens.push(Token { kind: TokenKind::Close, start: idx, end: idx + 1 }),
+                    _ => {}
+                }
+            } else if atom_start.is_none() {
+                // A string literal is a single atom, spaces and parens included
+                if let Some((open, close)) = Self::string_delimiters(&masked.as_bytes()[idx..])
+                    && let Some(pos) = masked[idx + open.len()..].find(close)
+                {
+                    string_end = idx + open.len() + pos + close.len();
+                    tokens.push(Token { kind: TokenKind::Atom, start: idx, end: string_end });
+                    continue;
+                }
+                atom_start = Some(idx);
+            }
+        }
+        
+        if let Some(start) = atom_start {
+            tokens.push(Token { kind: TokenKind::Atom, start, end: masked.len() });
+        }
+        
+        tokens
+    }
+    
+    /// Byte span of the name an atom spells, without the quotes of a `"quoted name"`.
+    fn name_span(masked: &str, token: Token) -> (usize, usize) {
+        let atom = &masked[token.start..token.end];
+        match Self::string_delimiters(atom.as_bytes()) {
+            Some((open, close)) if atom.len() >= open.len() + close.len() && atom.ends_with(close) => {
+                (token.start + open.len(), token.end - close.len())
+            }
+            _ => (token.start, token.end),
+        }
+    }
+    
+    /// Indices of the opening paren of every `(keyword ...)` form.
+    fn find_forms(masked: &str, tokens: &[Token], keyword: &str) -> Vec<usize> {
+        tokens
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| {
+                pair[0].kind == TokenKind::Open
+                    && pair[1].kind == TokenKind::Atom
+                    && &masked[pair[1].start..pair[1].end] == keyword
+            })
+            .map(|(open, _)| open)
+            .collect()
+    }
+    
+    /// Range from a form's opening paren through its keyword, e.g. `(deflayer`.
+    fn form_keyword_range(masked: &str, tokens: &[Token], open: usize) -> Range {
+        let end = tokens.get(open + 1).map(|token| token.end).unwrap_or(tokens[open].end);
+        Range {
+            start: Self::offset_to_position(masked, tokens[open].start),
+            end: Self::offset_to_position(masked, end),
+        }
+    }
+    
+    /// Range of a whole form, through the end of the text if it is never closed.
+    fn form_range(masked: &str, tokens: &[Token], open: usize) -> Range {
+        let (_, close) = Self::form_children(tokens, open);
+        let end = tokens.get(close).map_or(masked.len(), |close| close.end);
+        Range {
+            start: Self::offset_to_position(masked, tokens[open].start),
+            end: Self::offset_to_position(masked, end),
+        }
+    }
+    
+    /// Token index ranges of the direct children of the form opened at `tokens[open]`.
+    ///
+    /// The first child is usually the form's keyword. Also returns the index of the
+    /// closing paren, or `tokens.len()` if the form is never closed.
+    fn form_children(tokens: &[Token], open: usize) -> (Vec<std::ops::Range<usize>>, usize) {
+        let mut children = Vec::new();
+        let mut depth = 0;
+        let mut child_start = 0;
+        
+        for (idx, token) in tokens.iter().enumerate().skip(open + 1) {
+            match token.kind {
+                TokenKind::Open => {
+                    if depth == 0 {
+                        child_start = idx;
+                    }
+                    depth += 1;
+                }
+                TokenKind::Close => {
+                    if depth == 0 {
+                        return (children, idx);
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        children.push(child_start..idx + 1);
+                    }
+                }
+                TokenKind::Atom => {
+                    if depth == 0 {
+                        children.push(idx..idx + 1);
+                    }
+                }
+            }
+        }
+        
+        (children, tokens.len())
+    }
+    
+    /// Ranges of `layer` where it is used as a layer argument, e.g. `(layer-switch base)`.
+    ///
+    /// With `include_declaration` the name in `(deflayer base ...)` is included as well.
+    fn layer_name_ranges(masked: &str, layer: &str, include_declaration: bool) -> Vec<Range> {
+        if include_declaration {
+            let keywords: Vec<&str> = LAYER_ACTIONS.iter().copied().chain(["deflayer"]).collect();
+            Self::form_argument_ranges(masked, layer, &keywords)
+        } else {
+            Self::form_argument_ranges(masked, layer, LAYER_ACTIONS)
+        }
+    }
+    
+    /// Every `(layer-switch name)`-style form: its action, layer name and the name's range.
+    fn layer_action_targets(masked: &str) -> Vec<(&str, &str, Range)> {
+        let tokens = Self::tokenize(masked);
+        let mut targets = Vec::new();
+        
+        for window in tokens.windows(3) {
+            let (open, keyword, arg) = (window[0], window[1], window[2]);
+            if open.kind != TokenKind::Open || keyword.kind != TokenKind::Atom || arg.kind != TokenKind::Atom {
+                continue;
+            }
+            
+            let action = &masked[keyword.start..keyword.end];
+            if LAYER_ACTIONS.contains(&action) {
+                let (name_start, name_end) = Self::name_span(masked, arg);
+                targets.push((
+                    action,
+                    &masked[name_start..name_end],
+                    Range {
+                        start: Self::offset_to_position(masked, name_start),
+                        end: Self::offset_to_position(masked, name_end),
+                    },
+                ));
+            }
+        }
+        
+        targets
+    }
+    
+    /// Layer transitions across the indexed documents, ordered by file and position.
+    async fn layer_transitions(&self) -> Vec<LayerTransition> {
+        let indexed: Vec<Url> = self.symbols_cache.read().await.keys().cloned().collect();
+        let mut transitions = Vec::new();
+        for doc_uri in indexed {
+            let masked = match self.document_text(&doc_uri).await {
+                Some(text) => Self::mask_comments(&text),
+                None => continue,
+            };
+            for (action, layer, range) in Self::layer_action_targets(&masked) {
+                transitions.push(LayerTransition {
+                    location: Location {
+                        uri: doc_uri.clone(),
+                        range,
+                    },
+                    action: action.to_string(),
+                    layer: layer.to_string(),
+                    defined: false,
+                });
+            }
+        }
+        
+        let symbols = self.symbols_cache.read().await;
+        for transition in &mut transitions {
+            transition.defined = symbols
+                .values()
+                .any(|doc_symbols| doc_symbols.layers.contains_key(&transition.layer));
+        }
+        transitions.sort_by(|a, b| {
+            (a.location.uri.as_str(), a.location.range.start).cmp(&(b.location.uri.as_str(), b.location.range.start))
+        });
+        transitions
+    }
+    
+    /// Ranges of every `@alias` atom, including those inside other alias bodies.
+    ///
+    /// Whole atoms are compared, so `@ab` and `x@a` are not uses of `a`.
+    fn alias_reference_ranges(masked: &str, alias: &str) -> Vec<Range> {
+        Self::tokenize(masked)
+            .into_iter()
+            .filter(|token| {
+                token.kind == TokenKind::Atom && masked[token.start..token.end].strip_prefix('@') == Some(alias)
+            })
+            .map(|token| Range {
+                start: Self::offset_to_position(masked, token.start),
+                end: Self::offset_to_position(masked, token.end),
+            })
+            .collect()
+    }
+    
+    /// Ranges where `name` is the first argument of a form headed by one of `keywords`.
+    fn form_argument_ranges(masked: &str, name: &str, keywords: &[&str]) -> Vec<Range> {
+        let tokens = Self::tokenize(masked);
+        let mut ranges = Vec::new();
+        
+        for window in tokens.windows(3) {
+            let (open, keyword, arg) = (window[0], window[1], window[2]);
+            if open.kind != TokenKind::Open || keyword.kind != TokenKind::Atom || arg.kind != TokenKind::Atom {
+                continue;
+            }
+            
+            let (name_start, name_end) = Self::name_span(masked, arg);
+            if keywords.contains(&&masked[keyword.start..keyword.end]) && &masked[name_start..name_end] == name {
+                ranges.push(Range {
+                    start: Self::offset_to_position(masked, name_start),
+                    end: Self::offset_to_position(masked, name_end),
+                });
+            }
+        }
+        
+        ranges
+    }
+    
+    /// Warn about virtual key actions naming something no defseq or virtual key defines,
+    /// in the document or its includes.
+    ///
+    /// Template bodies are skipped; their names are often `$parameters`, only known once expanded.
+    async fn undefined_sequence_diagnostics(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        let symbols = self.symbols_cache.read().await;
+        let includes = self.includes.read().await;
+        let tokens = Self::tokenize(masked);
+        let template_spans: Vec<(usize, usize)> = Self::find_forms(masked, &tokens, "deftemplate")
+            .into_iter()
+            .map(|open| {
+                let (_, close) = Self::form_children(&tokens, open);
+                (tokens[open].start, tokens.get(close).map_or(masked.len(), |close| close.end))
+            })
+            .collect();
+        let mut diagnostics = Vec::new();
+        
+        for window in tokens.windows(3) {
+            let (open, keyword, arg) = (window[0], window[1], window[2]);
+            if open.kind != TokenKind::Open || keyword.kind != TokenKind::Atom || arg.kind != TokenKind::Atom {
+                continue;
+            }
+            
+            let name = &masked[arg.start..arg.end];
+            if !VIRTUAL_KEY_ACTIONS.contains(&&masked[keyword.start..keyword.end])
+                || template_spans.iter().any(|&(start, end)| (start..end).contains(&open.start))
+                || [SymbolKind::Sequence, SymbolKind::VirtualKey].into_iter().any(|kind| {
+                    Self::definition_kanata_uses(uri, &includes, &symbols, kind, name).is_some()
+                })
+            {
+                continue;
+            }
+            
+            diagnostics.push(Diagnostic {
+                range: Range {
+                    start: Self::offset_to_position(masked, arg.start),
+                    end: Self::offset_to_position(masked, arg.end),
+                },
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: DiagnosticCode::UndefinedVirtualKey.code(),
+                code_description: DiagnosticCode::UndefinedVirtualKey.description(),
+                source: Some("kanata-lsp".to_string()),
+                message: format!("Sequence or virtual key `{}` is not defined", name),
+                related_information: None,
+                tags: None,
+                data: None,
+            });
+        }
+        
+        diagnostics
+    }
+    
+    /// Selection ranges at `offset`: the token under the cursor, then each enclosing form.
+    fn selection_range_at(masked: &str, tokens: &[Token], offset: usize) -> SelectionRange {
+        // Byte spans from innermost to outermost
+        let mut spans = Vec::new();
+        
+        if let Some(atom) = tokens
+            .iter()
+            .find(|token| token.kind == TokenKind::Atom && token.start <= offset && offset <= token.end)
+        {
+            spans.push((atom.start, atom.end));
+        }
+        
+        // Enclosing forms close innermost first
+        let mut open_forms = Vec::new();
+        for token in tokens {
+            match token.kind {
+                TokenKind::Open => open_forms.push(token.start),
+                TokenKind::Close => {
+                    if let Some(start) = open_forms.pop()
+                        && start <= offset
+                        && offset <= token.end
+                    {
+                        spans.push((start, token.end));
+                    }
+                }
+                TokenKind::Atom => {}
+            }
+        }
+        
+        // Forms that are never closed extend to the end of the document
+        for &start in open_forms.iter().rev() {
+            if start <= offset {
+                spans.push((start, masked.len()));
+            }
+        }
+        
+        let to_range = |(start, end): (usize, usize)| Range {
+            start: Self::offset_to_position(masked, start),
+            end: Self::offset_to_position(masked, end),
+        };
+        
+        let mut spans = spans.into_iter().rev();
+        let outermost = spans.next().unwrap_or((offset, offset));
+        let mut selection = SelectionRange {
+            range: to_range(outermost),
+            parent: None,
+        };
+        for span in spans {
+            selection = SelectionRange {
+                range: to_range(span),
+                parent: Some(Box::new(selection)),
+            };
+        }
+        
+        selection
+    }
+    
+    /// Convert a byte offset into `text` into an LSP position, whose column counts UTF-16 units.
+    fn offset_to_position(text: &str, offset: usize) -> Position {
+        let before = &text[..offset];
+        let line = before.matches('\n').count();
+        let line_start = before.rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+        
+        Position {
+            line: line as u32,
+            character: before[line_start..].encode_utf16().count() as u32,
+        }
+    }
+    
+    /// Convert an LSP position into a byte offset into `text`, clamped to the line.
+    fn position_to_offset(text: &str, position: Position) -> usize {
+        let mut offset = 0;
+        
+        for (line_idx, line) in text.split_inclusive('\n').enumerate() {
+            if line_idx == position.line as usize {
+                // Walk the line in UTF-16 units; a column inside a surrogate pair stays on its char
+                let content = line.trim_end_matches(['\r', '\n']);
+                let mut units = 0;
+                for (col, c) in content.char_indices() {
+                    units += c.len_utf16();
+                    if units > position.character as usize {
+                        return offset + col;
+                    }
+                }
+                return offset + content.len();
+            }
+            offset += line.len();
+        }
+        
+        text.len()
+    }
+    
+    /// The forms enclosing `offset`, innermost first, with the argument index of the cursor.
+    ///
+    /// For the innermost form the index is the argument being typed (0 is the keyword);
+    /// for outer forms it is the argument holding the next form in.
+    fn enclosing_forms(masked: &str, offset: usize) -> Vec<(Option<&str>, usize)> {
+        // (keyword, number of children seen so far) for each open form
+        let mut stack: Vec<(Option<&str>, usize)> = Vec::new();
+        let tokens = Self::tokenize(&masked[..offset]);
+        
+        for token in &tokens {
+            match token.kind {
+                TokenKind::Open => {
+                    if let Some(parent) = stack.last_mut() {
+                        parent.1 += 1;
+                    }
+                    stack.push((None, 0));
+                }
+                TokenKind::Close => {
+                    stack.pop();
+                }
+                TokenKind::Atom => {
+                    if let Some(form) = stack.last_mut() {
+                        if form.1 == 0 {
+                            form.0 = Some(&masked[token.start..token.end]);
+                        }
+                        form.1 += 1;
+                    }
+                }
+            }
+        }
+        
+        // A word running right up to the cursor is the argument being typed
+        let typing_word = tokens
+            .last()
+            .is_some_and(|token| token.kind == TokenKind::Atom && token.end == offset);
+        
+        let innermost = stack.len().saturating_sub(1);
+        stack
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(level, &(keyword, children))| {
+                if level == innermost && !typing_word {
+                    (keyword, children)
+                } else {
+                    (keyword, children.saturating_sub(1))
+                }
+            })
+            .collect()
+    }
+    
+    /// Look the cursor position up in `COMPLETION_CONTEXTS`.
+    fn completion_context(masked: &str, offset: usize) -> Option<CompletionContext> {
+        let forms = Self::enclosing_forms(masked, offset);
+        
+        // defcfg alternates option names and their values
+        if let Some(&(Some("defcfg"), index)) = forms.first()
+            && index > 0
+        {
+            return Some(if index % 2 == 1 {
+                CompletionContext::DefcfgKey
+            } else {
+                CompletionContext::DefcfgValue
+            });
+        }
+        
+        COMPLETION_CONTEXTS
+            .iter()
+            .find(|&&(keyword, arg, in_list, _)| {
+                let level = if in_list { 1 } else { 0 };
+                forms.get(level).is_some_and(|&(form_keyword, index)| {
+                    form_keyword == Some(keyword) && arg.is_none_or(|arg| arg == index)
+                })
+            })
+            .map(|&(_, _, _, context)| context)
+            .or_else(|| {
+                // The first word of a list nested in a top-level form names an action
+                (forms.len() > 1 && forms[0].1 == 0).then_some(CompletionContext::Action)
+            })
+            .or_else(|| Self::is_alias_position(masked, offset, &forms).then_some(CompletionContext::Alias))
+    }
+    
+    /// Whether an `@alias` fits at the cursor: a word already started with `@`, a layer
+    /// item, a defalias or deflayermap binding, or an action argument other than a layer,
+    /// virtual key or template name.
+    fn is_alias_position(masked: &str, offset: usize, forms: &[(Option<&str>, usize)]) -> bool {
+        let word_start = masked[..offset]
+            .rfind(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .map_or(0, |idx| idx + 1);
+        if masked[word_start..offset].starts_with('@') {
+            return true;
+        }
+        
+        match forms {
+            [(Some("deflayer"), index)] => *index >= 2,
+            [(Some("defalias"), index)] => *index >= 2 && index % 2 == 0,
+            [(Some("deflayermap"), index)] => *index >= 3 && index % 2 == 1,
+            [(keyword, index), _, ..] => {
+                // Layer actions take nothing but the layer
+                if keyword.is_some_and(|keyword| LAYER_ACTIONS.contains(&keyword)) {
+                    return false;
+                }
+                let names_symbol = keyword.is_some_and(|keyword| {
+                    VIRTUAL_KEY_ACTIONS.contains(&keyword) || TEMPLATE_EXPANSIONS.contains(&keyword)
+                });
+                *index >= 1 && !(names_symbol && *index == 1)
+            }
+            _ => false,
+        }
+    }
+    
+    /// Tokens the parser expected, offered on the line of the parse error reporting them.
+    async fn expected_token_completions(&self, uri: &Url, position: Position) -> Vec<CompletionItem> {
+        let diagnostics_cache = self.diagnostics_cache.read().await;
+        let diagnostic = diagnostics_cache.get(uri).into_iter().flatten().find(|diagnostic| {
+            (diagnostic.range.start.line..=diagnostic.range.end.line).contains(&position.line)
+                && diagnostic.data.as_ref().is_some_and(|data| data.get("expected").is_some())
+        });
+        let expected = diagnostic
+            .and_then(|diagnostic| diagnostic.data.as_ref())
+            .and_then(|data| data.get("expected"))
+            .and_then(|expected| expected.as_array());
+        
+        expected
+            .into_iter()
+            .flatten()
+            .filter_map(|token| token.as_str())
+            .map(|token| CompletionItem {
+                label: token.to_string(),
+                kind: Some(CompletionItemKind::VALUE),
+                detail: Some("expected by parser".to_string()),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    fn defcfg_key_completions() -> Vec<CompletionItem> {
+        DEFCFG_OPTIONS
+            .iter()
+            .map(|option| CompletionItem {
+                label: option.name.to_string(),
+                kind: Some(CompletionItemKind::PROPERTY),
+                detail: Some(format!("defcfg option, {}", option.value.describe())),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    /// Allowed values of the defcfg option before the cursor.
+    fn defcfg_value_completions(masked: &str, offset: usize) -> Vec<CompletionItem> {
+        let tokens = Self::tokenize(masked);
+        let option = Self::find_forms(masked, &tokens, "defcfg").into_iter().find_map(|open| {
+            let (children, close) = Self::form_children(&tokens, open);
+            let end = tokens.get(close).map_or(masked.len(), |close| close.start);
+            if !(tokens[open].end..=end).contains(&offset) {
+                return None;
+            }
+            // The last item finished before the cursor; a word being typed ends right at it
+            let key = children[1..].iter().rev().find(|child| tokens[child.end - 1].end < offset)?;
+            defcfg::lookup(&masked[tokens[key.start].start..tokens[key.start].end])
+        });
+        
+        option
+            .into_iter()
+            .flat_map(|option| option.value.suggestions())
+            .map(|value| CompletionItem {
+                label: value.to_string(),
+                kind: Some(CompletionItemKind::VALUE),
+                detail: Some("defcfg value".to_string()),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    /// Action names, with their arguments as tab stops when the client supports snippets.
+    fn action_completions(snippet_support: bool) -> Vec<CompletionItem> {
+        ACTION_SNIPPETS
+            .iter()
+            .map(|&(name, arguments)| {
+                let (insert_text, insert_text_format) = if snippet_support {
+                    (format!("{} {}", name, arguments), InsertTextFormat::SNIPPET)
+                } else {
+                    (name.to_string(), InsertTextFormat::PLAIN_TEXT)
+                };
+                CompletionItem {
+                    label: name.to_string(),
+                    kind: Some(CompletionItemKind::FUNCTION),
+                    detail: Some("action".to_string()),
+                    insert_text: Some(insert_text),
+                    insert_text_format: Some(insert_text_format),
+                    ..CompletionItem::default()
+                }
+            })
+            .collect()
+    }
+    
+    /// Byte span of the defsrc item under `offset`, if any.
+    fn defsrc_item_at(masked: &str, offset: usize) -> Option<(usize, usize)> {
+        let tokens = Self::tokenize(masked);
+        let defsrc_open = *Self::find_forms(masked, &tokens, "defsrc").first()?;
+        let (children, _) = Self::form_children(&tokens, defsrc_open);
+        
+        children.get(1..)?.iter().find_map(|child| {
+            let token = &tokens[child.start];
+            (child.len() == 1 && token.kind == TokenKind::Atom && (token.start..=token.end).contains(&offset))
+                .then_some((token.start, token.end))
+        })
+    }
+    
+    /// Byte span of the defsrc item in the same position as the deflayer item at `offset`.
+    ///
+    /// Returns `None` outside a deflayer item or when the layer length doesn't match defsrc.
+    fn defsrc_item_for(masked: &str, offset: usize) -> Option<(usize, usize)> {
+        Self::layer_item_and_defsrc_item(masked, offset).map(|(_, defsrc_item)| defsrc_item)
+    }
+    
+    /// Byte spans of the deflayer item at `offset` and of the defsrc item it maps.
+    fn layer_item_and_defsrc_item(masked: &str, offset: usize) -> Option<((usize, usize), (usize, usize))> {
+        let tokens = Self::tokenize(masked);
+        let defsrc_open = *Self::find_forms(masked, &tokens, "defsrc").first()?;
+        let (defsrc_children, _) = Self::form_children(&tokens, defsrc_open);
+        let span = |child: &std::ops::Range<usize>| (tokens[child.start].start, tokens[child.end - 1].end);
+        
+        for open in Self::find_forms(masked, &tokens, "deflayer") {
+            // Skip the keyword and the layer name
+            let (children, _) = Self::form_children(&tokens, open);
+            let items = children.get(2..).unwrap_or_default();
+            let index = match items.iter().position(|item| {
+                let (start, end) = span(item);
+                (start..=end).contains(&offset)
+            }) {
+                Some(index) => index,
+                None => continue,
+            };
+            
+            if items.len() != defsrc_children.len() - 1 {
+                return None;
+            }
+            return Some((span(&items[index]), span(&defsrc_children[index + 1])));
+        }
+        
+        None
+    }
+    
+    /// Note on `hover` which defsrc key the deflayer item at `offset` maps to, or make a
+    /// hover of just that. Nothing is added when the layer's length doesn't match defsrc.
+    fn with_defsrc_key(masked: &str, offset: usize, hover: Option<Hover>) -> Option<Hover> {
+        let Some(((item_start, item_end), (key_start, key_end))) = Self::layer_item_and_defsrc_item(masked, offset)
+        else {
+            return hover;
+        };
+        let mapping = format!("Maps to defsrc key `{}`", &masked[key_start..key_end]);
+        
+        Some(match hover {
+            Some(Hover {
+                contents: HoverContents::Markup(MarkupContent { kind, value }),
+                range,
+            }) => Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind,
+                    value: format!("{}\n\n{}", value, mapping),
+                }),
+                range,
+            },
+            Some(other) => other,
+            None => Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: mapping,
+                }),
+                range: Some(Range {
+                    start: Self::offset_to_position(masked, item_start),
+                    end: Self::offset_to_position(masked, item_end),
+                }),
+            },
+        })
+    }
+    
+    /// The full definition of the `@alias` at `position`, however many lines its body takes.
+    async fn alias_hover(&self, uri: &Url, text: &str, masked: &str, position: Position) -> Option<Hover> {
+        let word = Self::get_word_at_position(text, position);
+        let name = word.strip_prefix('@')?;
+        if Self::get_word_at_position(masked, position) != word {
+            return None;
+        }
+        
+        let def = self.find_definition(uri, position).await?;
+        let def_text = self.document_text(&def.uri).await?;
+        let body_range = def.body_range?;
+        let body_start = Self::position_to_offset(&def_text, body_range.start);
+        let body_end = Self::position_to_offset(&def_text, body_range.end);
+        let body = &def_text[body_start..body_end];
+        
+        let offset = Self::position_to_offset(masked, position);
+        let start = masked[..offset].rfind(|c: char| c.is_whitespace() || c == '(' || c == ')').map_or(0, |idx| idx + 1);
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```kanata\n(defalias {} {})\n```", name, body),
+            }),
+            range: Some(Range {
+                start: Self::offset_to_position(masked, start),
+                end: Self::offset_to_position(masked, start + word.len()),
+            }),
+        })
+    }
+    
+    /// LSP version, plus the configured kanata binary's own `--version` output.
+    async fn version_report(&self) -> String {
+        let mut report = format!("kanata-lsp {}", env!("CARGO_PKG_VERSION"));
+        
+        let kanata_path = self.config.read().await.kanata_path.clone();
+        if let Some(path) = kanata_path {
+            let kanata_version = match tokio::process::Command::new(&path).arg("--version").output().await {
+                Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+                Ok(output) => format!("`{}` exited with {}", path.display(), output.status),
+                Err(e) => format!("`{}` could not be run: {}", path.display(), e),
+            };
+            report.push_str(&format!(", {}", kanata_version));
+        }
+        
+        report
+    }
+    
+    /// Insert a `#| ... |#` comment above layer `name` drawing its bindings as a grid of
+    /// boxes, one box per defsrc row.
+    fn layer_grid_edit(text: &str, name: &str) -> Result<TextEdit> {
+        let masked = Self::mask_comments(text);
+        let tokens = Self::tokenize(&masked);
+        let layout = Self::parse_defsrc_layout(&masked, DEFAULT_TAB_SIZE)
+            .ok_or_else(|| Error::invalid_params("document has no defsrc"))?;
+        
+        let open = Self::find_forms(&masked, &tokens, "deflayer")
+            .into_iter()
+            .find(|&open| {
+                tokens.get(open + 2).is_some_and(|&token| {
+                    let (name_start, name_end) = Self::name_span(&masked, token);
+                    &masked[name_start..name_end] == name
+                })
+            })
+            .ok_or_else(|| Error::invalid_params(format!("no layer named `{}`", name)))?;
+        let (children, _) = Self::form_children(&tokens, open);
+        let items: Vec<String> = children
+            .iter()
+            .skip(2)
+            .map(|child| {
+                // Nested actions may span lines; a `|#` inside would end the comment early
+                let item = &masked[tokens[child.start].start..tokens[child.end - 1].end];
+                item.split_whitespace().collect::<Vec<_>>().join(" ").replace("|#", "| #")
+            })
+            .collect();
+        let defsrc_len: usize = layout.iter().map(Vec::len).sum();
+        if items.len() != defsrc_len {
+            return Err(Error::invalid_params(format!(
+                "layer `{}` has {} keys but defsrc has {}",
+                name,
+                items.len(),
+                defsrc_len
+            )));
+        }
+        
+        let line_start = masked[..tokens[open].start].rfind('\n').map_or(0, |idx| idx + 1);
+        let indent = &masked[line_start..tokens[open].start];
+        let indent = if indent.trim().is_empty() { indent } else { "" };
+        
+        let mut lines = vec!["#|".to_string()];
+        let mut items = items.iter();
+        for row in &layout {
+            let cells: Vec<&String> = items.by_ref().take(row.len()).collect();
+            let widths: Vec<usize> = cells.iter().map(|cell| cell.graphemes(true).count()).collect();
+            let border = |left: &str, join: &str, right: &str| {
+                let segments: Vec<String> = widths.iter().map(|&width| "─".repeat(width + 2)).collect();
+                format!("{}{}{}", left, segments.join(join), right)
+            };
+            let contents: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!(" {}{} ", cell, " ".repeat(width - cell.graphemes(true).count())))
+                .collect();
+            lines.push(border("┌", "┬", "┐"));
+            lines.push(format!("│{}│", contents.join("│")));
+            lines.push(border("└", "┴", "┘"));
+        }
+        lines.push("|#".to_string());
+        
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let mut new_text = String::new();
+        for line in lines {
+            new_text.push_str(&line);
+            new_text.push_str(newline);
+            new_text.push_str(indent);
+        }
+        
+        let position = Self::offset_to_position(&masked, tokens[open].start);
+        Ok(TextEdit {
+            range: Range {
+                start: position,
+                end: position,
+            },
+            new_text,
+        })
+    }
+    
+    /// Insert a deflayer of transparent keys, laid out like defsrc, after the last deflayer.
+    async fn new_layer_edit(&self, uri: &Url, name: &str) -> Result<TextEdit> {
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+            return Err(Error::invalid_params(format!("`{}` is not a valid layer name", name)));
+        }
+        
+        let text = match self.documents.read().await.get(uri) {
+            Some(text) => text.clone(),
+            None => return Err(Error::invalid_params("document is not open")),
+        };
+        let masked = Self::mask_comments(&text);
+        if Self::extract_symbols(uri, &text).layers.contains_key(name) {
+            return Err(Error::invalid_params(format!("layer `{}` already exists", name)));
+        }
+        
+        let options = self.config.read().await.format.clone();
+        let layout = self
+            .defsrc_layout(uri, &text, options.tab_size)
+            .await
+            .ok_or_else(|| Error::invalid_params("document has no defsrc"))?;
+        
+        // Lay out a single-line skeleton the same way the formatter would
+        let defsrc_len: usize = layout.iter().map(Vec::len).sum();
+        let skeleton = format!("(deflayer {} {})", name, vec!["_"; defsrc_len].join(" "));
+        let (layer, _) = Self::format_deflayer(&[skeleton.as_str()], 0, &layout, &options);
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let layer = layer.replace('\n', newline);
+        
+        // Go after the last deflayer, falling back to defsrc itself
+        let tokens = Self::tokenize(&masked);
+        let anchor = Self::find_forms(&masked, &tokens, "deflayer")
+            .last()
+            .or(Self::find_forms(&masked, &tokens, "defsrc").first())
+            .map(|&open| Self::form_children(&tokens, open).1)
+            .and_then(|close| tokens.get(close))
+            .map_or(text.len(), |close| close.end);
+        let position = Self::offset_to_position(&masked, anchor);
+        
+        Ok(TextEdit {
+            range: Range {
+                start: position,
+                end: position,
+            },
+            new_text: format!("{}{}{}", newline, newline, layer),
+        })
+    }
+    
+    /// Sorted names of the symbols of `kind` defined in the document or the files it includes.
+    async fn defined_names(&self, uri: &Url, kind: SymbolKind) -> Vec<String> {
+        self.defined_symbols(uri, kind).await.into_iter().map(|(name, _)| name).collect()
+    }
+    
+    /// Like `defined_names`, with each name's definition. A name defined more than once
+    /// keeps the document's own definition, then the one from the earliest include.
+    async fn defined_symbols(&self, uri: &Url, kind: SymbolKind) -> Vec<(String, Definition)> {
+        let symbols = self.symbols_cache.read().await;
+        let includes = self.includes.read().await;
+        
+        let mut defined: Vec<(String, Definition)> = std::iter::once(uri)
+            .chain(includes.get(uri).into_iter().flatten().map(|(_, include_uri)| include_uri))
+            .filter_map(|doc_uri| symbols.get(doc_uri))
+            .flat_map(|doc_symbols| doc_symbols.definitions(kind).iter().map(|(name, def)| (name.clone(), def.clone())))
+            .collect();
+        defined.sort_by(|(a, _), (b, _)| a.cmp(b));
+        defined.dedup_by(|(a, _), (b, _)| a == b);
+        defined
+    }
+    
+    /// Let items match a word typed in the wrong case, e.g. `TAP-HOLD` for `tap-hold`.
+    ///
+    /// Matching items filter on the label spelled with the typed prefix's case and replace
+    /// the typed word with the label's own spelling, noting the correction in the detail.
+    fn correct_typed_case(items: &mut [CompletionItem], masked: &str, offset: usize) {
+        let word_start = masked[..offset]
+            .rfind(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .map_or(0, |idx| idx + 1);
+        let typed = &masked[word_start..offset];
+        if !typed.contains(|c: char| c.is_uppercase()) {
+            return;
+        }
+        let range = Range {
+            start: Self::offset_to_position(masked, word_start),
+            end: Self::offset_to_position(masked, offset),
+        };
+        
+        for item in items.iter_mut().filter(|item| item.text_edit.is_none()) {
+            let label = &item.label;
+            let matches = label
+                .get(..typed.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(typed) && prefix != typed);
+            if !matches {
+                continue;
+            }
+            
+            item.filter_text = Some(format!("{}{}", typed, &label[typed.len()..]));
+            item.text_edit = Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: item.insert_text.take().unwrap_or_else(|| label.clone()),
+            }));
+            let note = format!("case corrected from `{}`", typed);
+            item.detail = Some(match item.detail.take() {
+                Some(detail) => format!("{}, {}", detail, note),
+                None => note,
+            });
+        }
+    }
+    
+    /// Aliases as `@name`, replacing the word typed so far whether or not it has the `@`.
+    /// The detail previews what each alias expands to.
+    async fn alias_completions(&self, uri: &Url, masked: &str, offset: usize) -> Vec<CompletionItem> {
+        let word_start = masked[..offset]
+            .rfind(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .map_or(0, |idx| idx + 1);
+        let range = Range {
+            start: Self::offset_to_position(masked, word_start),
+            end: Self::offset_to_position(masked, offset),
+        };
+        let typed_at = masked[word_start..offset].starts_with('@');
+        
+        // Bodies come from the text of whichever file defines each alias
+        let mut texts: HashMap<Url, Option<String>> = HashMap::new();
+        let mut aliases = Vec::new();
+        for (name, def) in self.defined_symbols(uri, SymbolKind::Alias).await {
+            if !texts.contains_key(&def.uri) {
+                let text = self.document_text(&def.uri).await;
+                texts.insert(def.uri.clone(), text);
+            }
+            let body = def.body_range.zip(texts[&def.uri].as_deref()).map(|(range, text)| {
+                let start = Self::position_to_offset(text, range.start);
+                let end = Self::position_to_offset(text, range.end);
+                Self::body_preview(&text[start..end])
+            });
+            aliases.push((name, body));
+        }
+        
+        aliases
+            .into_iter()
+            .map(|(name, body)| CompletionItem {
+                label: format!("@{}", name),
+                kind: Some(CompletionItemKind::VARIABLE),
+                detail: Some(body.unwrap_or_else(|| "alias".to_string())),
+                filter_text: Some(if typed_at { format!("@{}", name) } else { name.clone() }),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                    range,
+                    new_text: format!("@{}", name),
+                })),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    /// An alias body on one line, cut to 60 characters with an ellipsis.
+    fn body_preview(body: &str) -> String {
+        const MAX_CHARS: usize = 60;
+        let flat = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        if flat.chars().count() <= MAX_CHARS {
+            return flat;
+        }
+        let mut preview: String = flat.chars().take(MAX_CHARS - 1).collect();
+        preview.push('…');
+        preview
+    }
+    
+    /// Layer names defined in the document or the files it includes.
+    async fn layer_completions(&self, uri: &Url) -> Vec<CompletionItem> {
+        self.defined_names(uri, SymbolKind::Layer)
+            .await
+            .into_iter()
+            .map(|name| CompletionItem {
+                label: name.clone(),
+                kind: Some(CompletionItemKind::MODULE),
+                detail: Some("layer".to_string()),
+                // A name with spaces only works quoted
+                insert_text: name.contains(char::is_whitespace).then(|| format!("\"{}\"", name)),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    
+    fn get_word_at_position(text: &str, position: Position) -> String {
+        let offset = Self::position_to_offset(text, position);
+        let line_start = text[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = text[offset..].find(['\r', '\n']).map_or(text.len(), |idx| offset + idx);
+        let line = &text[line_start..line_end];
+        let cursor = offset - line_start;
+        
+        if cursor >= line.len() {
+            return String::new();
+        }
+        
+        // Inside a quoted name the whole name is the word
+        if let Some(token) = Self::tokenize(line)
+            .into_iter()
+            .find(|token| token.start <= cursor && cursor < token.end && line[token.start..].starts_with(['"', 'r']))
+        {
+            let (start, end) = Self::name_span(line, token);
+            if (start, end) != (token.start, token.end) {
+                return line[start..end].to_string();
+            }
+        }
+        
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+        
+        // Find the start of the word (including @ for aliases)
+        let start = line[..cursor]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_word_char(c) || c == '@')
+            .last()
+            .map_or(cursor, |(idx, _)| idx);
+        
+        // Find the end of the word; a cursor on the `@` itself still means the alias after it
+        let mut end = cursor;
+        if line[cursor..].starts_with('@') {
+            end += 1;
+        }
+        end += line[end..].find(|c: char| !is_word_char(c)).unwrap_or(line.len() - end);
+        
+        line[start..end].to_string()
+    }
+    
+    fn extract_symbols(uri: &Url, text: &str) -> DocumentSymbols {
+        let mut layers = HashMap::new();
+        
+        // Ignore anything inside comments, e.g. a commented-out `(defalias`
+        let masked = Self::mask_comments(text);
+        
+        // defalias, defseq and virtual key forms hold any number of name/body pairs
+        let tokens = Self::tokenize(&masked);
+        let aliases = Self::pair_definitions(uri, &masked, &tokens, "defalias");
+        let sequences = Self::pair_definitions(uri, &masked, &tokens, "defseq");
+        let mut virtual_keys = HashMap::new();
+        for keyword in VIRTUAL_KEY_FORMS {
+            virtual_keys.extend(Self::pair_definitions(uri, &masked, &tokens, keyword));
+        }
+        let (templates, template_params) = Self::template_definitions(uri, &masked, &tokens);
+        
+        // A layer's name is the first item of its deflayer, wherever the line breaks fall
+        for open in Self::find_forms(&masked, &tokens, "deflayer") {
+            let (children, _) = Self::form_children(&tokens, open);
+            let name = match children.get(1) {
+                Some(child) if tokens[child.start].kind == TokenKind::Atom => tokens[child.start],
+                _ => continue,
+            };
+            let (name_start, name_end) = Self::name_span(&masked, name);
+            
+            layers.insert(masked[name_start..name_end].to_string(), Definition {
+                uri: uri.clone(),
+                range: Range {
+                    start: Self::offset_to_position(&masked, name_start),
+                    end: Self::offset_to_position(&masked, name_end),
+                },
+                keyword_range: Self::form_keyword_range(&masked, &tokens, open),
+                body_range: None,
+                full_range: Self::form_range(&masked, &tokens, open),
+            });
+        }
+        
+        let mut defining_lines = Vec::new();
+        // Many forms, so look lines up in an index rather than rescanning the text each time
+        let line_starts: Vec<usize> = masked.match_indices('\n').map(|(idx, _)| idx + 1).collect();
+        let line_of = |offset: usize| line_starts.partition_point(|&start| start <= offset) as u32;
+        for keyword in ["defalias", "defseq", "deftemplate"].iter().chain(VIRTUAL_KEY_FORMS) {
+            for open in Self::find_forms(&masked, &tokens, keyword) {
+                let (_, close) = Self::form_children(&tokens, open);
+                let end = tokens.get(close).map_or(masked.len(), |close| close.end);
+                defining_lines.push((line_of(tokens[open].start), line_of(end)));
+            }
+        }
+        for open in Self::find_forms(&masked, &tokens, "deflayer") {
+            let (children, close) = Self::form_children(&tokens, open);
+            let end = match children.get(1) {
+                Some(name) => tokens[name.end - 1].end,
+                None => tokens.get(close).map_or(masked.len(), |close| close.end),
+            };
+            defining_lines.push((line_of(tokens[open].start), line_of(end)));
+        }
+        let (referenced_aliases, referenced_layers) = Self::referenced_names(&masked);
+        
+        DocumentSymbols {
+            aliases,
+            layers,
+            sequences,
+            virtual_keys,
+            templates,
+            template_params,
+            defining_lines,
+            referenced_aliases,
+            referenced_layers,
+        }
+    }
+    
+    /// Definitions and parameter lists of `(deftemplate name (params...) body...)` forms.
+    fn template_definitions(
+        uri: &Url,
+        masked: &str,
+        tokens: &[Token],
+    ) -> (HashMap<String, Definition>, HashMap<String, Vec<String>>) {
+        let mut templates = HashMap::new();
+        let mut template_params = HashMap::new();
+        
+        for open in Self::find_forms(masked, tokens, "deftemplate") {
+            let (children, _) = Self::form_children(tokens, open);
+            let name = match children.get(1) {
+                Some(child) if tokens[child.start].kind == TokenKind::Atom => tokens[child.start],
+                _ => continue,
+            };
+            let name_text = masked[name.start..name.end].to_string();
+            
+            // Parameters are the atoms of the list following the name
+            let params = children
+                .get(2)
+                .map(|list| {
+                    tokens[list.clone()]
+                        .iter()
+                        .filter(|token| token.kind == TokenKind::Atom)
+                        .map(|token| masked[token.start..token.end].to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            
+            templates.insert(name_text.clone(), Definition {
+                uri: uri.clone(),
+                range: Range {
+                    start: Self::offset_to_position(masked, name.start),
+                    end: Self::offset_to_position(masked, name.end),
+                },
+                keyword_range: Self::form_keyword_range(masked, tokens, open),
+                body_range: None,
+                full_range: Self::form_range(masked, tokens, open),
+            });
+            template_params.insert(name_text, params);
+        }
+        
+        (templates, template_params)
+    }
+    
+    /// Warn about defcfg options Kanata doesn't know and values they don't accept.
+    fn defcfg_diagnostics(masked: &str) -> Vec<Diagnostic> {
+        let tokens = Self::tokenize(masked);
+        let mut diagnostics = Vec::new();
+        let span_range = |start: usize, end: usize| Range {
+            start: Self::offset_to_position(masked, start),
+            end: Self::offset_to_position(masked, end),
+        };
+        
+        for open in Self::find_forms(masked, &tokens, "defcfg") {
+            let (children, _) = Self::form_children(&tokens, open);
+            for pair in children[1..].chunks(2) {
+                let key = tokens[pair[0].start];
+                if key.kind != TokenKind::Atom {
+                    continue;
+                }
+                let name = &masked[key.start..key.end];
+                
+                // The table may lag behind Kanata, so an unknown name is only a hint
+                let (code, severity, range, message) = match (defcfg::lookup(name), pair.get(1)) {
+                    (None, _) => (
+                        DiagnosticCode::UnknownDefcfgOption,
+                        DiagnosticSeverity::HINT,
+                        span_range(key.start, key.end),
+                        format!("Unknown defcfg option `{}`", name),
+                    ),
+                    (Some(option), Some(value)) => {
+                        let (start, end) = (tokens[value.start].start, tokens[value.end - 1].end);
+                        // Lists such as `(all-except f1)` and quoted strings are left to the parser
+                        let is_plain_atom = value.len() == 1
+                            && tokens[value.start].kind == TokenKind::Atom
+                            && Self::string_delimiters(&masked.as_bytes()[start..]).is_none();
+                        if !is_plain_atom || option.value.accepts(&masked[start..end]) {
+                            continue;
+                        }
+                        (
+                            DiagnosticCode::InvalidDefcfgValue,
+                            DiagnosticSeverity::WARNING,
+                            span_range(start, end),
+                            format!("`{}` expects {}", name, option.value.describe()),
+                        )
+                    }
+                    // A missing value is left to the parser
+                    (Some(_), None) => continue,
+                };
+                
+                diagnostics.push(Diagnostic {
+                    range,
+                    severity: Some(severity),
+                    code: code.code(),
+                    code_description: code.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message,
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                });
+            }
+        }
+        
+        diagnostics
+    }
+    
+    /// Values of defcfg options that name a symbol, with the symbol kind and the name's span.
+    fn defcfg_names(masked: &str) -> Vec<(SymbolKind, &str, (usize, usize))> {
+        let tokens = Self::tokenize(masked);
+        let mut names = Vec::new();
+        
+        for open in Self::find_forms(masked, &tokens, "defcfg") {
+            let (children, _) = Self::form_children(&tokens, open);
+            for pair in children[1..].chunks(2) {
+                let (key, value) = match pair {
+                    [key, value] if value.len() == 1 => (tokens[key.start], tokens[value.start]),
+                    _ => continue,
+                };
+                let kind = match defcfg::lookup(&masked[key.start..key.end]).map(|option| option.value) {
+                    Some(defcfg::ValueKind::Name(kind)) if value.kind == TokenKind::Atom => kind,
+                    _ => continue,
+                };
+                let (start, end) = Self::name_span(masked, value);
+                names.push((kind, &masked[start..end], (start, end)));
+            }
+        }
+        
+        names
+    }
+    
+    /// Error on defcfg options naming a symbol defined neither in the document nor its includes.
+    async fn defcfg_name_diagnostics(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        let symbols = self.symbols_cache.read().await;
+        let includes = self.includes.read().await;
+        
+        Self::defcfg_names(masked)
+            .into_iter()
+            .filter(|&(kind, name, _)| Self::definition_kanata_uses(uri, &includes, &symbols, kind, name).is_none())
+            .map(|(kind, name, (start, end))| Diagnostic {
+                range: Range {
+                    start: Self::offset_to_position(masked, start),
+                    end: Self::offset_to_position(masked, end),
+                },
+                severity: Some(DiagnosticSeverity::ERROR),
+                code: DiagnosticCode::UndefinedDefcfgName.code(),
+                code_description: DiagnosticCode::UndefinedDefcfgName.description(),
+                source: Some("kanata-lsp".to_string()),
+                message: format!("{} `{}` is not defined", kind.label(), name),
+                related_information: None,
+                tags: None,
+                data: None,
+            })
+            .collect()
+    }
+    
+    /// Hint at defsrc keys spelled with an alias rather than their canonical name.
+    fn noncanonical_keycode_diagnostics(masked: &str) -> Vec<Diagnostic> {
+        let tokens = Self::tokenize(masked);
+        let local_keys = Self::local_keys(masked);
+        let mut diagnostics = Vec::new();
+        
+        for open in Self::find_forms(masked, &tokens, "defsrc") {
+            let (children, _) = Self::form_children(&tokens, open);
+            for child in children.iter().skip(1) {
+                let token = tokens[child.start];
+                if token.kind != TokenKind::Atom {
+                    continue;
+                }
+                let name = &masked[token.start..token.end];
+                // A local key of the same name is what the config means, not the builtin
+                if local_keys.iter().any(|&(_, local, _)| local == name) {
+                    continue;
+                }
+                let canonical = match canonical_name(name) {
+                    Some(canonical) => canonical,
+                    None => continue,
+                };
+                
+                diagnostics.push(Diagnostic {
+                    range: Range {
+                        start: Self::offset_to_position(masked, token.start),
+                        end: Self::offset_to_position(masked, token.end),
+                    },
+                    severity: Some(DiagnosticSeverity::HINT),
+                    code: DiagnosticCode::NoncanonicalKeycode.code(),
+                    code_description: DiagnosticCode::NoncanonicalKeycode.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!("`{}` is an alias of `{}`; prefer the canonical name", name, canonical),
+                    related_information: None,
+                    tags: None,
+                    data: Some(serde_json::json!({ "canonical": canonical })),
+                });
+            }
+        }
+        
+        diagnostics
+    }
+    
+    /// Hint at deflayers that are transparent apart from at most `threshold` keys,
+    /// which usually means the layer is unfinished.
+    fn sparse_layer_diagnostics(masked: &str, threshold: usize) -> Vec<Diagnostic> {
+        if threshold == 0 {
+            return vec![];
+        }
+        
+        let tokens = Self::tokenize(masked);
+        let defsrc_len = match Self::find_forms(masked, &tokens, "defsrc").first() {
+            Some(&open) => Self::form_children(&tokens, open).0.len() - 1,
+            None => return vec![],
+        };
+        if defsrc_len <= threshold {
+            return vec![];
+        }
+        
+        let mut diagnostics = Vec::new();
+        for open in Self::find_forms(masked, &tokens, "deflayer") {
+            // Skip the keyword and the layer name; count mismatches are reported by the parser
+            let (children, _) = Self::form_children(&tokens, open);
+            let items = children.get(2..).unwrap_or_default();
+            if items.len() != defsrc_len {
+                continue;
+            }
+            
+            let mapped = items
+                .iter()
+                .filter(|item| {
+                    let token = tokens[item.start];
+                    token.kind != TokenKind::Atom || &masked[token.start..token.end] != "_"
+                })
+                .count();
+            if mapped > threshold {
+                continue;
+            }
+            
+            diagnostics.push(Diagnostic {
+                range: Self::form_keyword_range(masked, &tokens, open),
+                severity: Some(DiagnosticSeverity::HINT),
+                code: DiagnosticCode::SparseLayer.code(),
+                code_description: DiagnosticCode::SparseLayer.description(),
+                source: Some("kanata-lsp".to_string()),
+                message: format!(
+                    "Layer maps only {} of {} keys; the rest are transparent. Is it unfinished?",
+                    mapped, defsrc_len
+                ),
+                related_information: None,
+                tags: None,
+                data: None,
+            });
+        }
+        
+        diagnostics
+    }
+    
+    /// Flag anything outside a top-level form, like the `x` of `(deflayer base ...) x`.
+    ///
+    /// The diagnostic's data holds the range to remove for the quick fix: the token and
+    /// the spaces before it on its line, so `) x` becomes `)`.
+    fn stray_token_diagnostics(masked: &str) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut depth = 0usize;
+        
+        for token in Self::tokenize(masked) {
+            match token.kind {
+                TokenKind::Open => depth += 1,
+                TokenKind::Close => depth = depth.saturating_sub(1),
+                TokenKind::Atom if depth == 0 => {
+                    let line_start = masked[..token.start].rfind('\n').map_or(0, |idx| idx + 1);
+                    let remove_start = masked[line_start..token.start]
+                        .trim_end_matches([' ', '\t'])
+                        .len()
+                        + line_start;
+                    // A token alone on its line leaves the line's indentation alone
+                    let remove_start = if remove_start == line_start { token.start } else { remove_start };
+                    let remove = Range {
+                        start: Self::offset_to_position(masked, remove_start),
+                        end: Self::offset_to_position(masked, token.end),
+                    };
+                    diagnostics.push(Diagnostic {
+                        range: Range {
+                            start: Self::offset_to_position(masked, token.start),
+                            end: Self::offset_to_position(masked, token.end),
+                        },
+                        severity: Some(DiagnosticSeverity::ERROR),
+                        code: DiagnosticCode::StrayToken.code(),
+                        code_description: DiagnosticCode::StrayToken.description(),
+                        source: Some("kanata-lsp".to_string()),
+                        message: format!("`{}` is outside any form", &masked[token.start..token.end]),
+                        related_information: None,
+                        tags: None,
+                        data: Some(serde_json::json!({ "remove": remove })),
+                    });
+                }
+                TokenKind::Atom => {}
+            }
+        }
+        
+        diagnostics
+    }
+    
+    /// Flag a `deflayer` or `defalias` whose first item is a list or absent, so no name.
+    ///
+    /// The parser's own error for these is hard to connect to the form, and the form is
+    /// left out of the symbol index, so the opening paren is pointed at instead.
+    fn missing_name_diagnostics(masked: &str) -> Vec<Diagnostic> {
+        let tokens = Self::tokenize(masked);
+        let mut diagnostics = Vec::new();
+        
+        for keyword in ["deflayer", "defalias"] {
+            for open in Self::find_forms(masked, &tokens, keyword) {
+                let (children, _) = Self::form_children(&tokens, open);
+                let named = children.get(1).is_some_and(|name| tokens[name.start].kind == TokenKind::Atom);
+                if named {
+                    continue;
+                }
+                
+                let paren = tokens[open];
+                diagnostics.push(Diagnostic {
+                    range: Range {
+                        start: Self::offset_to_position(masked, paren.start),
+                        end: Self::offset_to_position(masked, paren.end),
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: DiagnosticCode::MissingName.code(),
+                    code_description: DiagnosticCode::MissingName.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!("{} is missing a name", keyword),
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                });
+            }
+        }
+        
+        diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+        diagnostics
+    }
+    
+    /// Flag `tap-hold*` timeouts that are zero, unusually long, or look swapped.
+    ///
+    /// The first two arguments are the tap (repress) timeout and the hold timeout. Only
+    /// literal numbers are checked, so template parameters are left alone.
+    fn tap_hold_timing_diagnostics(masked: &str, limits: TapHoldLimits) -> Vec<Diagnostic> {
+        let tokens = Self::tokenize(masked);
+        let mut diagnostics = Vec::new();
+        let diagnostic = |start: usize, end: usize, severity, message| Diagnostic {
+            range: Range {
+                start: Self::offset_to_position(masked, start),
+                end: Self::offset_to_position(masked, end),
+            },
+            severity: Some(severity),
+            code: DiagnosticCode::TapHoldTiming.code(),
+            code_description: DiagnosticCode::TapHoldTiming.description(),
+            source: Some("kanata-lsp".to_string()),
+            message,
+            related_information: None,
+            tags: None,
+            data: None,
+        };
+        
+        for (open, pair) in tokens.windows(2).enumerate() {
+            if pair[0].kind != TokenKind::Open
+                || pair[1].kind != TokenKind::Atom
+                || !masked[pair[1].start..pair[1].end].starts_with("tap-hold")
+            {
+                continue;
+            }
+            
+            let (children, _) = Self::form_children(&tokens, open);
+            let timeouts: Vec<(Token, u64)> = children
+                .iter()
+                .skip(1)
+                .take(2)
+                .filter_map(|child| {
+                    let token = tokens[child.start];
+                    let value = masked[token.start..token.end].parse().ok()?;
+                    (child.len() == 1).then_some((token, value))
+                })
+                .collect();
+            let [(tap, tap_ms), (hold, hold_ms)] = timeouts[..] else {
+                continue;
+            };
+            
+            if hold_ms == 0 {
+                diagnostics.push(diagnostic(
+                    hold.start,
+                    hold.end,
+                    DiagnosticSeverity::WARNING,
+                    "Hold timeout of 0 ms makes the key act as its hold action right away".to_string(),
+                ));
+            }
+            if tap_ms == 0 {
+                diagnostics.push(diagnostic(
+                    tap.start,
+                    tap.end,
+                    DiagnosticSeverity::HINT,
+                    "Tap timeout of 0 ms means a quick re-press never repeats the tap action".to_string(),
+                ));
+            }
+            if limits.max_timeout_ms > 0 {
+                for (token, ms, label) in [(tap, tap_ms, "Tap"), (hold, hold_ms, "Hold")] {
+                    if ms > limits.max_timeout_ms {
+                        diagnostics.push(diagnostic(
+                            token.start,
+                            token.end,
+                            DiagnosticSeverity::HINT,
+                            format!("{} timeout of {} ms is unusually long", label, ms),
+                        ));
+                    }
+                }
+            }
+            if limits.swap_ratio > 0 && hold_ms > 0 && tap_ms >= hold_ms.saturating_mul(limits.swap_ratio) {
+                diagnostics.push(diagnostic(
+                    tap.start,
+                    hold.end,
+                    DiagnosticSeverity::HINT,
+                    format!(
+                        "Tap timeout {} ms is far longer than hold timeout {} ms; are they swapped?",
+                        tap_ms, hold_ms
+                    ),
+                ));
+            }
+        }
+        
+        diagnostics
+    }
+    
+    /// Warn about template expansions of templates defined neither in the document nor its
+    /// includes, or with the wrong argument count.
+    async fn template_call_diagnostics(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        let symbols = self.symbols_cache.read().await;
+        let includes = self.includes.read().await;
+        let tokens = Self::tokenize(masked);
+        let mut diagnostics = Vec::new();
+        
+        for keyword in TEMPLATE_EXPANSIONS {
+            for open in Self::find_forms(masked, &tokens, keyword) {
+                let (children, _) = Self::form_children(&tokens, open);
+                let name = match children.get(1) {
+                    Some(child) if tokens[child.start].kind == TokenKind::Atom => tokens[child.start],
+                    _ => continue,
+                };
+                let name_text = &masked[name.start..name.end];
+                let arg_count = children.len() - 2;
+                
+                let params = Self::definition_kanata_uses(uri, &includes, &symbols, SymbolKind::Template, name_text)
+                    .and_then(|def| symbols.get(&def.uri)?.template_params.get(name_text));
+                let (code, message) = match params {
+                    None => (DiagnosticCode::UndefinedTemplate, format!("Template `{}` is not defined", name_text)),
+                    Some(params) if params.len() != arg_count => (
+                        DiagnosticCode::TemplateArity,
+                        format!(
+                            "Template `{}` expects {} argument(s) but got {}",
+                            name_text,
+                            params.len(),
+                            arg_count
+                        ),
+                    ),
+                    Some(_) => continue,
+                };
+                
+                diagnostics.push(Diagnostic {
+                    range: Range {
+                        start: Self::offset_to_position(masked, name.start),
+                        end: Self::offset_to_position(masked, name.end),
+                    },
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: code.code(),
+                    code_description: code.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message,
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                });
+            }
+        }
+        
+        diagnostics
+    }
+    
+    /// Definitions for every name of `(keyword name body name body ...)` forms.
+    fn pair_definitions(uri: &Url, masked: &str, tokens: &[Token], keyword: &str) -> HashMap<String, Definition> {
+        let mut definitions = HashMap::new();
+        
+        for (open, pair) in tokens.windows(2).enumerate() {
+            if pair[0].kind != TokenKind::Open || &masked[pair[1].start..pair[1].end] != keyword {
+                continue;
+            }
+            
+            let keyword_range = Range {
+                start: Self::offset_to_position(masked, pair[0].start),
+                end: Self::offset_to_position(masked, pair[1].end),
+            };
+            
+            let full_range = Self::form_range(masked, tokens, open);
+            let (children, _) = Self::form_children(tokens, open);
+            for name_and_body in children[1..].chunks(2) {
+                let name = tokens[name_and_body[0].start];
+                if name.kind != TokenKind::Atom {
+                    continue;
+                }
+                let (name_start, name_end) = Self::name_span(masked, name);
+                let body_range = name_and_body.get(1).map(|body| Range {
+                    start: Self::offset_to_position(masked, tokens[body.start].start),
+                    end: Self::offset_to_position(masked, tokens[body.end - 1].end),
+                });
+                
+                definitions.insert(masked[name_start..name_end].to_string(), Definition {
+                    uri: uri.clone(),
+                    range: Range {
+                        start: Self::offset_to_position(masked, name_start),
+                        end: Self::offset_to_position(masked, name_end),
+                    },
+                    keyword_range,
+                    body_range,
+                    full_range,
+                });
+            }
+        }
+        
+        definitions
+    }
+    
+    /// Replace every item of the deflayer around `offset` with `_`, padded to keep columns.
+    ///
+    /// Editor undo brings the bindings back. Returns `None` when the layer is already blank.
+    fn blank_layer_edits(masked: &str, offset: usize) -> Option<Vec<TextEdit>> {
+        let tokens = Self::tokenize(masked);
+        
+        for open in Self::find_forms(masked, &tokens, "deflayer") {
+            let (children, close) = Self::form_children(&tokens, open);
+            let end = tokens.get(close).map_or(masked.len(), |close| close.end);
+            if !(tokens[open].start..=end).contains(&offset) {
+                continue;
+            }
+            
+            // Skip the keyword and the layer name
+            let edits: Vec<TextEdit> = children
+                .get(2..)?
+                .iter()
+                .filter_map(|item| {
+                    let (start, end) = (tokens[item.start].start, tokens[item.end - 1].end);
+                    let item_text = &masked[start..end];
+                    if item_text == "_" {
+                        return None;
+                    }
+                    
+                    // Pad to the item's width unless it ends the line or spans lines
+                    let at_line_end = masked[end..].starts_with(['\r', '\n']) || end == masked.len();
+                    let width = if at_line_end || item_text.contains('\n') {
+                        1
+                    } else {
+                        item_text.graphemes(true).count()
+                    };
+                    Some(TextEdit {
+                        range: Range {
+                            start: Self::offset_to_position(masked, start),
+                            end: Self::offset_to_position(masked, end),
+                        },
+                        new_text: format!("{:<width$}", "_", width = width),
+                    })
+                })
+                .collect();
+            return (!edits.is_empty()).then_some(edits);
+        }
+        
+        None
+    }
+    
+    /// Wrap the deflayer item at `offset` in a `tap-hold` with placeholder timeouts and
+    /// hold action, or reduce a `tap-hold*` item back to its tap action.
+    ///
+    /// With a defsrc layout the whole layer is reformatted so its columns stay aligned;
+    /// otherwise, or if the layer can't be formatted, only the item is replaced.
+    fn tap_hold_toggle_edit(
+        text: &str,
+        layout: Option<&[Vec<usize>]>,
+        options: &FormatOptions,
+        offset: usize,
+    ) -> Option<(&'static str, TextEdit)> {
+        let masked = Self::mask_comments(text);
+        let tokens = Self::tokenize(&masked);
+        
+        let (open, item) = Self::find_forms(&masked, &tokens, "deflayer").into_iter().find_map(|open| {
+            let (children, _) = Self::form_children(&tokens, open);
+            let item = children.get(2..)?.iter().find(|item| {
+                (tokens[item.start].start..=tokens[item.end - 1].end).contains(&offset)
+            })?;
+            Some((open, item.clone()))
+        })?;
+        let (start, end) = (tokens[item.start].start, tokens[item.end - 1].end);
+        
+        let (title, replacement) = if item.len() == 1 {
+            ("Wrap in tap-hold", format!("(tap-hold 200 200 {} @layer)", &text[start..end]))
+        } else {
+            let keyword = tokens.get(item.start + 1).filter(|token| token.kind == TokenKind::Atom)?;
+            if !masked[keyword.start..keyword.end].starts_with("tap-hold") {
+                return None;
+            }
+            let (children, _) = Self::form_children(&tokens, item.start);
+            let tap = children.get(3)?;
+            ("Unwrap tap-hold", text[tokens[tap.start].start..tokens[tap.end - 1].end].to_string())
+        };
+        let item_edit = TextEdit {
+            range: Range {
+                start: Self::offset_to_position(&masked, start),
+                end: Self::offset_to_position(&masked, end),
+            },
+            new_text: replacement.clone(),
+        };
+        
+        // Reformat the edited layer; it starts on the same line before and after the edit
+        let Some(layout) = layout else {
+            return Some((title, item_edit));
+        };
+        let edited = format!("{}{}{}", &text[..start], replacement, &text[end..]);
+        let first_line = Self::offset_to_position(&masked, tokens[open].start).line as usize;
+        let Some((_, edited_end, formatted)) = Self::formatted_deflayers(&edited, &Self::mask_comments(&edited), layout, options)
+            .into_iter()
+            .find(|&(layer_start, _, _)| layer_start == first_line)
+        else {
+            return Some((title, item_edit));
+        };
+        
+        // Lines removed by unwrapping a multi-line item all came from this layer
+        let lines: Vec<&str> = text.lines().collect();
+        let end_line = edited_end + lines.len() - edited.lines().count();
+        let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        Some((title, TextEdit {
+            range: Range {
+                start: Position::new(first_line as u32, 0),
+                end: Position::new(end_line as u32 - 1, lines[end_line - 1].encode_utf16().count() as u32),
+            },
+            new_text: formatted.replace('\n', line_ending),
+        }))
+    }
+    
+    /// Rewrite the multi-pair defalias around `offset` as one `(defalias name body)` form per pair.
+    fn split_defalias_edit(text: &str, masked: &str, offset: usize) -> Option<TextEdit> {
+        let tokens = Self::tokenize(masked);
+        
+        for open in Self::find_forms(masked, &tokens, "defalias") {
+            let (children, close) = Self::form_children(&tokens, open);
+            let close = tokens.get(close)?;
+            let (start, end) = (tokens[open].start, close.end);
+            if !(start..=end).contains(&offset) {
+                continue;
+            }
+            
+            // Only groups of complete pairs are worth splitting
+            let pairs = &children[1..];
+            if pairs.len() < 4 || pairs.len() % 2 != 0 {
+                return None;
+            }
+            
+            // Rebuilding the forms would drop comments, so leave those groups alone
+            if text[start..end] != masked[start..end] {
+                return None;
+            }
+            
+            let line_start = text[..start].rfind('\n').map_or(0, |idx| idx + 1);
+            let indent: String = text[line_start..start]
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .collect();
+            let child_text = |child: &std::ops::Range<usize>| {
+                &text[tokens[child.start].start..tokens[child.end - 1].end]
+            };
+            
+            let forms: Vec<String> = pairs
+                .chunks(2)
+                .map(|pair| format!("(defalias {} {})", child_text(&pair[0]), child_text(&pair[1])))
+                .collect();
+            
+            return Some(TextEdit {
+                range: Range {
+                    start: Self::offset_to_position(masked, start),
+                    end: Self::offset_to_position(masked, end),
+                },
+                new_text: forms.join(&format!("\n{}", indent)),
+            });
+        }
+        
+        None
+    }
+    
+    /// Reorder every alias pair in the document's defalias forms by name.
+    ///
+    /// Each form keeps its number of pairs; the pairs are dealt into the existing slots
+    /// in sorted order, taking along the comment lines just above each name and a comment
+    /// trailing its body. Since an alias can only use aliases defined before it, sorting
+    /// is refused when it would move an alias after one that uses it.
+    fn sort_aliases_edits(text: &str) -> Result<Vec<TextEdit>> {
+        let masked = Self::mask_comments_and_strings(text);
+        let tokens = Self::tokenize(&masked);
+        let span = |child: &std::ops::Range<usize>| (tokens[child.start].start, tokens[child.end - 1].end);
+        
+        // (name, slot start, slot end, body child) for each pair in document order
+        let mut pairs = Vec::new();
+        for open in Self::find_forms(&masked, &tokens, "defalias") {
+            let (children, _) = Self::form_children(&tokens, open);
+            let mut previous_end = span(&children[0]).1;
+            for pair in children[1..].chunks(2) {
+                let [name, body] = pair else {
+                    return Err(Error::invalid_params("a defalias has an alias without a body"));
+                };
+                let (name_start, name_end) = span(name);
+                let (_, body_end) = span(body);
+                
+                // Comment lines directly above the name belong to it
+                let mut start = name_start;
+                let line_start = text[..name_start].rfind('\n').map_or(0, |idx| idx + 1);
+                if masked[line_start..name_start].trim().is_empty() {
+                    let mut line_start = line_start;
+                    while line_start > previous_end {
+                        let above = text[..line_start - 1].rfind('\n').map_or(0, |idx| idx + 1).max(previous_end);
+                        let line = text[above..line_start - 1].trim();
+                        if !line.starts_with(";;") {
+                            break;
+                        }
+                        start = above + text[above..].find(";;").unwrap_or(0);
+                        line_start = above;
+                    }
+                }
+                
+                // So does a comment after the body on the same line
+                let line_end = text[body_end..].find(['\r', '\n']).map_or(text.len(), |pos| body_end + pos);
+                let mut end = body_end;
+                if masked[body_end..line_end].trim().is_empty() && !text[body_end..line_end].trim().is_empty() {
+                    end = body_end + text[body_end..line_end].trim_end().len();
+                }
+                
+                pairs.push((&masked[name_start..name_end], start, end, body.clone()));
+                previous_end = end;
+            }
+        }
+        
+        let mut sorted: Vec<usize> = (0..pairs.len()).collect();
+        sorted.sort_by_key(|&idx| pairs[idx].0);
+        
+        // Every alias used in a body must still come before it
+        let names: HashSet<&str> = pairs.iter().map(|pair| pair.0).collect();
+        let mut defined = HashSet::new();
+        for &idx in &sorted {
+            let (name, _, _, body) = &pairs[idx];
+            for token in &tokens[body.clone()] {
+                let used = match masked[token.start..token.end].strip_prefix('@') {
+                    Some(used) if token.kind == TokenKind::Atom && names.contains(used) => used,
+                    _ => continue,
+                };
+                if used != *name && !defined.contains(used) {
+                    return Err(Error::invalid_params(format!(
+                        "sorting would define alias `{}` after `{}`, which uses it",
+                        used, name
+                    )));
+                }
+            }
+            defined.insert(*name);
+        }
+        
+        let mut edits = Vec::new();
+        for (slot, &idx) in pairs.iter().zip(&sorted) {
+            let (_, start, end, _) = *slot;
+            let (_, moved_start, moved_end, _) = pairs[idx];
+            if start == moved_start {
+                continue;
+            }
+            
+            // A trailing comment moved in front of more code on the line would hide it
+            let mut new_text = text[moved_start..moved_end].to_string();
+            let line_end = text[end..].find(['\r', '\n']).map_or(text.len(), |pos| end + pos);
+            if masked[moved_start..moved_end].trim_end().len() < moved_end - moved_start
+                && !text[end..line_end].trim().is_empty()
+            {
+                let line_start = text[..end].rfind('\n').map_or(0, |idx| idx + 1);
+                let indent: String = text[line_start..].chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+                new_text.push('\n');
+                new_text.push_str(&indent);
+            }
+            
+            edits.push(TextEdit {
+                range: Range {
+                    start: Self::offset_to_position(&masked, start),
+                    end: Self::offset_to_position(&masked, end),
+                },
+                new_text,
+            });
+        }
+        
+        Ok(edits)
+    }
+    
+    /// Tokens a parser error says it expected, from an "expected one of: a, b, c" style line.
+    fn expected_tokens(error_msg: &str) -> Vec<String> {
+        let line = match error_msg.lines().find(|line| line.to_ascii_lowercase().contains("expected")) {
+            Some(line) => line,
+            None => return vec![],
+        };
+        let start = line.to_ascii_lowercase().find("expected").unwrap_or(0) + "expected".len();
+        let list = line[start..]
+            .trim_start_matches(|c: char| c.is_whitespace() || c == ':')
+            .trim_start_matches("one of")
+            .trim_start_matches(|c: char| c.is_whitespace() || c == ':');
+        
+        list.split(',')
+            .flat_map(|item| item.split(" or "))
+            .map(|item| item.trim().trim_matches(|c: char| matches!(c, '`' | '"' | '\'' | '.')).trim())
+            .filter(|item| !item.is_empty() && !item.contains(char::is_whitespace))
+            .map(str::to_string)
+            .collect()
+    }
+    
+    /// Collect the `file.kbd:line:col` locations in a parser error other than the primary line.
+    fn extract_related_locations(
+        error_msg: &str,
+        uri: &Url,
+        temp_file: &Path,
+        primary_line: u32,
+    ) -> Vec<DiagnosticRelatedInformation> {
+        let mut related: Vec<DiagnosticRelatedInformation> = Vec::new();
+        let mut rest = error_msg;
+        
+        while let Some(idx) = rest.find(".kbd:") {
+            let before = &rest[..idx];
+            let file_start = before
+                .rfind(|c: char| c == '[' || c.is_whitespace())
+                .map(|pos| pos + 1)
+                .unwrap_or(0);
+            let file = format!("{}.kbd", &before[file_start..]);
+            
+            let after = &rest[idx + 5..];
+            let coords_end = after
+                .find(|c: char| !(c.is_ascii_digit() || c == ':'))
+                .unwrap_or(after.len());
+            let mut coords = after[..coords_end].split(':').map(|part| part.parse::<u32>());
+            rest = &after[coords_end..];
+            
+            let (line, col) = match (coords.next(), coords.next()) {
+                (Some(Ok(line)), Some(Ok(col))) => (line.saturating_sub(1), col.saturating_sub(1)),
+                _ => continue,
+            };
+            
+            // The parser only knows the temp file, which stands in for this document
+            let location_uri = if Path::new(&file) == temp_file || Path::new(&file).file_name() == temp_file.file_name() {
+                if line == primary_line {
+                    continue;
+                }
+                uri.clone()
+            } else {
+                match Url::from_file_path(&file) {
+                    Ok(file_uri) => file_uri,
+                    Err(_) => continue,
+                }
+            };
+            
+            let position = Position { line, character: col };
+            let location = Location {
+                uri: location_uri,
+                range: Range { start: position, end: position },
+            };
+            if related.iter().any(|info| info.location == location) {
+                continue;
+            }
+            
+            related.push(DiagnosticRelatedInformation {
+                location,
+                message: "Related location".to_string(),
+            });
+        }
+        
+        related
+    }
+    
+    fn extract_line_info(error_msg: &str) -> (u32, u32, u32) {
+        // Try to extract line number from the visual range markers first
+        // Format: "79 │ ╭─▶" to "85 │ ├─▶"
+        let mut start_line = None;
+        let mut end_line = None;
+        
+        for line in error_msg.lines() {
+            // Look for the start marker
+            if (line.contains("╭─▶") || line.contains("│ ╭─▶")) && start_line.is_none() {
+                if let Some(num_str) = line.split('│').next() {
+                    if let Ok(num) = num_str.trim().parse::<u32>() {
+                        start_line = Some(num.saturating_sub(1)); // Convert to 0-based
+                    }
+                }
+            }
+            // Look for the end marker
+            if line.contains("├─▶") || line.contains("╰──") {
+                if let Some(num_str) = line.split('│').next() {
+                    if let Ok(num) = num_str.trim().parse::<u32>() {
+                        end_line = Some(num.saturating_sub(1)); // Convert to 0-based
+                    }
+                }
+            }
+        }
+        
+        // If we found both start and end markers, use them
+        if let (Some(start), Some(end)) = (start_line, end_line) {
+            return (start, 0, end);
+        }
+        
+        // Fallback: Look for line:col in brackets like [file.kbd:78:1]
+        if let Some(start) = error_msg.find(".kbd:") {
+            let after_kbd = &error_msg[start + 5..];
+            if let Some(end) = after_kbd.find(']') {
+                let coords = &after_kbd[..end];
+                let parts: Vec<&str> = coords.split(':').collect();
+                
+                if parts.len() >= 2 {
+                    if let (Ok(line), Ok(col)) = (parts[0].parse::<u32>(), parts[1].parse::<u32>()) {
+                        let line_0 = line.saturating_sub(1);
+                        let col_0 = col.saturating_sub(1);
+                        return (line_0, col_0, line_0);
+                    }
+                }
+            }
+        }
+        
+        // Default to line 0 if we can't parse
+        (0, 0, 0)
+    }
+
+    /// Index the files pulled in by `(include ...)` and report the ones that can't be loaded.
+    async fn index_includes(&self, uri: &Url, masked: &str) -> Vec<Diagnostic> {
+        // Relative paths need a location on disk to resolve against; a document that
+        // isn't a local file, such as an untitled buffer, only has the config root
+        let file_path = uri.to_file_path().ok();
+        let config_root = self.config.read().await.config_root.clone();
+        if file_path.is_none() && config_root.is_none() {
+            return vec![];
+        }
+        
+        let tokens = Self::tokenize(masked);
+        let mut included = Vec::new();
+        let mut diagnostics = Vec::new();
+        
+        for (open, arg, include_path) in Self::include_paths(masked, &tokens) {
+            let resolved = Self::resolve_include(file_path.as_deref(), include_path, config_root.as_deref());
+            let loaded = resolved.as_ref().and_then(|path| {
+                let include_uri = Url::from_file_path(path).ok()?;
+                let include_text = std::fs::read_to_string(path).ok()?;
+                Some((include_uri, include_text))
+            });
+            
+            match loaded {
+                Some((include_uri, include_text)) => {
+                    let include_symbols = Self::extract_symbols(&include_uri, &include_text);
+                    self.symbols_cache.write().await.insert(include_uri.clone(), include_symbols);
+                    included.push((Self::offset_to_position(masked, open.start), include_uri));
+                }
+                None => {
+                    let message = match resolved {
+                        Some(path) => format!("Included file `{}` could not be read", path.display()),
+                        None => format!("Included file `{}` not found", include_path),
+                    };
+                    diagnostics.push(Diagnostic {
+                        range: Range {
+                            start: Self::offset_to_position(masked, arg.start),
+                            end: Self::offset_to_position(masked, arg.end),
+                        },
+                        severity: Some(DiagnosticSeverity::ERROR),
+                        code: DiagnosticCode::MissingInclude.code(),
+                        code_description: DiagnosticCode::MissingInclude.description(),
+                        source: Some("kanata-lsp".to_string()),
+                        message,
+                        related_information: None,
+                        tags: None,
+                        data: None,
+                    });
+                }
+            }
+        }
+        
+        // Keep the reverse map in sync so changes to included files reach this document
+        let mut includers = self.includers.write().await;
+        for dependents in includers.values_mut() {
+            dependents.remove(uri);
+        }
+        for (_, include_uri) in &included {
+            includers.entry(include_uri.clone()).or_default().insert(uri.clone());
+        }
+        includers.retain(|_, dependents| !dependents.is_empty());
+        
+        self.includes.write().await.insert(uri.clone(), included);
+        diagnostics
+    }
+    
+    /// Each `include` form's opening paren, path token and path.
+    ///
+    /// The path may be written bare, `(include foo.kbd)`, or quoted, `(include "foo.kbd")`;
+    /// quotes are not part of the path.
+    fn include_paths<'a>(masked: &'a str, tokens: &[Token]) -> Vec<(Token, Token, &'a str)> {
+        Self::find_forms(masked, tokens, "include")
+            .into_iter()
+            .filter_map(|open| {
+                let arg = tokens.get(open + 2).filter(|token| token.kind == TokenKind::Atom)?;
+                let (path_start, path_end) = Self::name_span(masked, *arg);
+                Some((tokens[open], *arg, &masked[path_start..path_end]))
+            })
+            .collect()
+    }
+    
+    /// Extract symbols from the `.kbd` files under `roots` so cross-file features work
+    /// before those files are opened. Files already in the cache are left alone, since
+    /// an open document's symbols are newer than what is on disk.
+    async fn index_workspace(
+        client: Client,
+        symbols_cache: Arc<RwLock<HashMap<Url, DocumentSymbols>>>,
+        roots: Vec<PathBuf>,
+        options: IndexOptions,
+    ) {
+        let max_files = options.max_files;
+        let scan = tokio::task::spawn_blocking(move || {
+            let (paths, truncated) = Self::workspace_config_files(&roots, &options);
+            let symbols: Vec<(Url, DocumentSymbols)> = paths
+                .into_iter()
+                .filter_map(|path| {
+                    let uri = Url::from_file_path(&path).ok()?;
+                    let text = std::fs::read_to_string(&path).ok()?;
+                    let symbols = Self::extract_symbols(&uri, &text);
+                    Some((uri, symbols))
+                })
+                .collect();
+            (symbols, truncated)
+        });
+        let (symbols, truncated) = match scan.await {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                client
+                    .log_message(MessageType::WARNING, format!("Workspace indexing failed: {}", e))
+                    .await;
+                return;
+            }
+        };
+        
+        let indexed = symbols.len();
+        let mut cache = symbols_cache.write().await;
+        for (uri, symbols) in symbols {
+            cache.entry(uri).or_insert(symbols);
+        }
+        drop(cache);
+        
+        let mut message = format!("Indexed {} workspace config file(s)", indexed);
+        if truncated {
+            message.push_str(&format!("; stopped at the index.maxFiles limit of {}", max_files));
+        }
+        client.log_message(MessageType::INFO, message).await;
+    }
+    
+    /// Keep the workspace index in step with files changed on disk that aren't open,
+    /// leaving out excluded ones just as the startup scan does.
+    async fn reindex_watched_files(&self, changes: &[FileEvent]) {
+        let config = self.config.read().await.clone();
+        if config.index.max_files == 0 {
+            return;
+        }
+        
+        for change in changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            let in_workspace = config.workspace_roots.iter().any(|root| path.starts_with(root));
+            if !in_workspace
+                || Self::is_excluded(&path, &config.workspace_roots, &config.index.exclude)
+                || self.documents.read().await.contains_key(&change.uri)
+            {
+                continue;
+            }
+            
+            let text = match change.typ {
+                FileChangeType::DELETED => None,
+                _ => tokio::fs::read_to_string(&path).await.ok(),
+            };
+            let mut cache = self.symbols_cache.write().await;
+            match text {
+                Some(text) => {
+                    cache.insert(change.uri.clone(), Self::extract_symbols(&change.uri, &text));
+                }
+                None => {
+                    cache.remove(&change.uri);
+                }
+            }
+        }
+    }
+    
+    /// Up to `options.max_files` `.kbd` files under `roots` that aren't excluded, and
+    /// whether more were left out.
+    ///
+    /// Hidden directories such as `.git` are skipped, as are symlinked directories, so
+    /// a link cycle can't keep the walk going.
+    fn workspace_config_files(roots: &[PathBuf], options: &IndexOptions) -> (Vec<PathBuf>, bool) {
+        let mut files = Vec::new();
+        let mut pending: Vec<PathBuf> = roots.to_vec();
+        
+        while let Some(dir) = pending.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut entries: Vec<_> = entries.flatten().collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let path = entry.path();
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                if Self::is_excluded(&path, roots, &options.exclude) {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    if !entry.file_name().to_string_lossy().starts_with('.') {
+                        pending.push(path);
+                    }
+                } else if path.extension().is_some_and(|ext| ext == "kbd") {
+                    if files.len() == options.max_files {
+                        return (files, true);
+                    }
+                    files.push(path);
+                }
+            }
+        }
+        
+        (files, false)
+    }
+    
+    /// Whether `path`, or a directory it is in, matches one of the `exclude` globs.
+    ///
+    /// A glob with a `/` is matched against the path relative to its workspace folder;
+    /// one without is matched against each file and directory name, so `examples`
+    /// leaves out every directory of that name.
+    fn is_excluded(path: &Path, roots: &[PathBuf], exclude: &[String]) -> bool {
+        let Some(relative) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) else {
+            return false;
+        };
+        let components: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        
+        (1..=components.len()).any(|len| {
+            let prefix = components[..len].join("/");
+            exclude.iter().any(|glob| {
+                let subject = if glob.contains('/') { &prefix } else { &components[len - 1] };
+                Self::glob_matches(glob.trim_start_matches("./"), subject)
+            })
+        })
+    }
+    
+    /// Match `text` against a glob where `*` and `?` stay within one path segment
+    /// and `**` crosses segments; `**/` also matches no directories at all.
+    fn glob_matches(glob: &str, text: &str) -> bool {
+        if let Some(rest) = glob.strip_prefix("**/") {
+            return Self::glob_matches(rest, text)
+                || text.match_indices('/').any(|(idx, _)| Self::glob_matches(rest, &text[idx + 1..]));
+        }
+        if let Some(rest) = glob.strip_prefix("**") {
+            return text.char_indices().any(|(idx, _)| Self::glob_matches(rest, &text[idx..]))
+                || Self::glob_matches(rest, "");
+        }
+        
+        let mut chars = glob.chars();
+        match chars.next() {
+            None => text.is_empty(),
+            Some('*') => {
+                let segment_end = text.find('/').unwrap_or(text.len());
+                (0..=segment_end)
+                    .filter(|&idx| text.is_char_boundary(idx))
+                    .any(|idx| Self::glob_matches(chars.as_str(), &text[idx..]))
+            }
+            Some('?') => text
+                .chars()
+                .next()
+                .is_some_and(|c| c != '/' && Self::glob_matches(chars.as_str(), &text[c.len_utf8()..])),
+            Some(c) => text.strip_prefix(c).is_some_and(|rest| Self::glob_matches(chars.as_str(), rest)),
+        }
+    }
+    
+    /// Resolve an include path relative to the including file, then to the config root.
+    ///
+    /// The result has no `.` or `..` components, so its URI is the one the client uses
+    /// for that file rather than another spelling of it.
+    fn resolve_include(including_file: Option<&Path>, include_path: &str, config_root: Option<&Path>) -> Option<PathBuf> {
+        let relative_to_file = including_file.and_then(Path::parent).map(|dir| dir.join(include_path));
+        let relative_to_root = config_root.map(|root| root.join(include_path));
+        
+        relative_to_file
+            .into_iter()
+            .chain(relative_to_root)
+            .find(|candidate| candidate.is_file())
+            .map(|path| Self::normalize_path(&path))
+    }
+    
+    /// Drop `.` components and apply `..` ones without touching the file system, keeping
+    /// any root or Windows drive prefix. `Url::from_file_path` keeps both kinds as written.
+    fn normalize_path(path: &Path) -> PathBuf {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                std::path::Component::CurDir => {}
+                // The parent of the root is the root; a relative path keeps its leading `..`
+                std::path::Component::ParentDir => match normalized.components().next_back() {
+                    Some(std::path::Component::RootDir) => {}
+                    None | Some(std::path::Component::Prefix(_) | std::path::Component::ParentDir) => {
+                        normalized.push("..");
+                    }
+                    Some(_) => {
+                        normalized.pop();
+                    }
+                },
+                _ => normalized.push(component),
+            }
+        }
+        normalized
+    }
+    
+    /// Whether applying `change` to `text` leaves its extracted symbols valid as they are.
+    ///
+    /// That holds for an edit within one line, outside every stretch that defines
+    /// something, that neither adds nor removes line breaks or characters that change
+    /// the structure (parens, quotes, comment markers). Definitions then keep both
+    /// their names and their positions.
+    fn change_keeps_symbols(text: &str, change: &TextDocumentContentChangeEvent, symbols: &DocumentSymbols) -> bool {
+        let range = match change.range {
+            Some(range) if range.start.line == range.end.line => range,
+            _ => return false,
+        };
+        let line = range.start.line;
+        if symbols.defining_lines.iter().any(|&(first, last)| (first..=last).contains(&line)) {
+            return false;
+        }
+        
+        let start = Self::position_to_offset(text, range.start);
+        let end = Self::position_to_offset(text, range.end).max(start);
+        let structural = ['(', ')', '"', ';', '#', '|', '\n', '\r'];
+        !change.text.contains(structural) && !text[start..end].contains(structural)
+    }
+    
+    /// The message a panic was raised with, if it has one.
+    fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+        payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "the parser panicked".to_string())
+    }
+    
+    /// Name for the temporary copy of a document, based on its own file name.
+    fn temp_file_name(uri: &Url) -> String {
+        uri.to_file_path()
+            .ok()
+            .and_then(|path| path.file_stem().map(|stem| format!("{}.kbd", stem.to_string_lossy())))
+            .unwrap_or_else(|| "kanata-temp.kbd".to_string())
+    }
+    
+    /// Path for the temporary copy of a document, in a directory of its own.
+    ///
+    /// No two validations share a file, even of documents with the same name, so a parse
+    /// still running after a timeout can't have its input replaced or deleted.
+    fn temp_file_path(uri: &Url) -> PathBuf {
+        let validation = VALIDATION_COUNT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir()
+            .join("kanata-lsp")
+            .join(format!("{}-{}", std::process::id(), validation))
+            .join(Self::temp_file_name(uri))
+    }
+    
+    /// Refresh a document's symbols without validating it, for when validation waits
+    /// for a save or only happens on open.
+    async fn index_document(&self, uri: &Url, text: &str) {
+        self.reference_cache.write().await.clear();
+        self.symbols_cache.write().await.insert(uri.clone(), Self::extract_symbols(uri, text));
+    }
+    
+    async fn validate_document(&self, uri: &Url, text: &str) {
+        self.validate_document_with_symbols(uri, text, None).await;
+    }
+    
+    /// Validate a document, reusing `symbols` instead of extracting them when given.
+    async fn validate_document_with_symbols(&self, uri: &Url, text: &str, symbols: Option<DocumentSymbols>) {
+        // Any edit can add or remove references anywhere
+        self.reference_cache.write().await.clear();
+        
+        // Extract symbols from the document
+        let symbols = symbols.unwrap_or_else(|| Self::extract_symbols(uri, text));
+        let masked = Self::mask_comments(text);
+        let mut symbol_diagnostics = Self::noncanonical_keycode_diagnostics(&masked);
+        symbol_diagnostics.extend(Self::defcfg_diagnostics(&masked));
+        symbol_diagnostics.extend(Self::missing_name_diagnostics(&masked));
+        symbol_diagnostics.extend(Self::stray_token_diagnostics(&masked));
+        let sparse_layer_threshold = self.config.read().await.sparse_layer_threshold;
+        symbol_diagnostics.extend(Self::sparse_layer_diagnostics(&masked, sparse_layer_threshold));
+        let tap_hold_limits = self.config.read().await.tap_hold;
+        symbol_diagnostics.extend(Self::tap_hold_timing_diagnostics(&masked, tap_hold_limits));
+        self.symbols_cache.write().await.insert(uri.clone(), symbols);
+        symbol_diagnostics.extend(self.index_includes(uri, &masked).await);
+        symbol_diagnostics.extend(self.undefined_sequence_diagnostics(uri, &masked).await);
+        symbol_diagnostics.extend(self.template_call_diagnostics(uri, &masked).await);
+        symbol_diagnostics.extend(self.defcfg_name_diagnostics(uri, &masked).await);
+        symbol_diagnostics.extend(self.unused_symbol_diagnostics(uri, &masked).await);
+        symbol_diagnostics.extend(self.missing_defsrc_diagnostics(uri, &masked).await);
+        
+        // Write text to a temporary file and parse it, all off the async runtime.
+        // Keeping the original file name makes paths echoed in parser errors recognizable.
+        let temp_file = Self::temp_file_path(uri);
+        let timeout = self.config.read().await.validation_timeout;
+        let temp_text = text.to_string();
+        let parse_file = temp_file.clone();
+        let parse_task = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            if let Some(temp_dir) = parse_file.parent() {
+                std::fs::create_dir_all(temp_dir)?;
+            }
+            std::fs::write(&parse_file, temp_text)?;
+            
+            // Parse the file and immediately convert to error string. A panic in the
+            // parser is caught so it becomes a diagnostic instead of a lost validation.
+            let parsed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                kanata_parser::cfg::new_from_file(&parse_file)
+                    .map(|_| ())
+                    .map_err(|e| format!("{:?}", e))
+            }));
+            let result = match parsed {
+                Ok(Ok(())) => ParseOutcome::Valid,
+                Ok(Err(error_msg)) => ParseOutcome::Invalid(error_msg),
+                Err(payload) => ParseOutcome::Panicked(Self::panic_message(payload.as_ref())),
+            };
+            
+            // Don't leave a copy of every validated config behind in the temp directory,
+            // nor the directory that held only this copy
+            let _ = std::fs::remove_file(&parse_file);
+            if let Some(temp_dir) = parse_file.parent() {
+                let _ = std::fs::remove_dir(temp_dir);
+            }
+            Ok(result)
+        });
+        
+        let parse_result = tokio::time::timeout(timeout, parse_task)
+            .await
+            .map(|joined| joined.unwrap_or_else(|e| Ok(ParseOutcome::Panicked(e.to_string()))));
+        
+        let mut diagnostics = match parse_result {
+            Err(_) => {
+                // The parser is still running, give up waiting on it
+                vec![Diagnostic {
+                    range: Range {
+                        start: Position { line: 0, character: 0 },
+                        end: Position { line: 0, character: 0 },
+                    },
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: DiagnosticCode::ValidationTimeout.code(),
+                    code_description: DiagnosticCode::ValidationTimeout.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!(
+                        "Validation timed out after {} ms",
+                        timeout.as_millis()
+                    ),
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                }]
+            }
+            Ok(Err(e)) => {
+                vec![Diagnostic {
+                    range: Range {
+                        start: Position { line: 0, character: 0 },
+                        end: Position { line: 0, character: 0 },
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: DiagnosticCode::TempFile.code(),
+                    code_description: DiagnosticCode::TempFile.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!("Failed to write temp file: {}", e),
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                }]
+            }
+            Ok(Ok(ParseOutcome::Valid)) => {
+                // Parsing succeeded, no diagnostics
+                vec![]
+            }
+            Ok(Ok(ParseOutcome::Panicked(panic_msg))) => {
+                vec![Diagnostic {
+                    range: Range {
+                        start: Position { line: 0, character: 0 },
+                        end: Position { line: 0, character: 0 },
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: DiagnosticCode::ParserPanic.code(),
+                    code_description: DiagnosticCode::ParserPanic.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: format!(
+                        "Internal parser error, please report it to kanata with this config: {}",
+                        panic_msg
+                    ),
+                    related_information: None,
+                    tags: None,
+                    data: None,
+                }]
+            }
+            Ok(Ok(ParseOutcome::Invalid(error_msg))) => {
+                // Parse error - create diagnostic
+                
+                // Extract line information from error message
+                let (start_line, start_col, end_line) = Self::extract_line_info(&error_msg);
+                
+                // Get the actual line length (in UTF-16 units, as LSP columns are)
+                // to avoid going past end of line
+                let line_len = |line_idx: u32| {
+                    text.lines().nth(line_idx as usize)
+                        .map(|line| line.encode_utf16().count() as u32)
+                };
+                let start_col = match line_len(start_line) {
+                    Some(len) => start_col.min(len),
+                    None => start_col,
+                };
+                let end_col = if start_line == end_line {
+                    // Single line diagnostic - highlight from start_col to end of line
+                    let line_len = line_len(start_line).unwrap_or(start_col + 1);
+                    // Ensure end_col is at least 1 character after start_col
+                    line_len.max(start_col + 1)
+                } else {
+                    // Multi-line diagnostic - highlight to end of end_line
+                    line_len(end_line).unwrap_or(0)
+                };
+                
+                // Log the error message for debugging
+                self.log_trace(TraceValue::Verbose, format!(
+                    "Full error: {}",
+                    error_msg.lines().take(15).collect::<Vec<_>>().join(" || ")
+                ))
+                .await;
+                
+                // Extract just the text after "help:"
+                let display_message = error_msg.lines()
+                    .find(|line| line.contains("help:"))
+                    .and_then(|line| line.split("help:").nth(1))
+                    .map(|s| s.trim().to_string())
+                    .unwrap_or_else(|| "Parse error".to_string());
+                
+                self.log_trace(TraceValue::Messages, format!("Extracted message: {}", display_message)).await;
+                
+                // Secondary locations, e.g. where a duplicate was first defined
+                let related = Self::extract_related_locations(&error_msg, uri, &temp_file, start_line);
+                
+                // Kept on the diagnostic so completion can offer them at the error
+                let expected = Self::expected_tokens(&error_msg);
+                
+                // Ensure the range is valid
+                let (final_start_line, final_start_col, final_end_line, final_end_col) = 
+                    if start_line > end_line || (start_line == end_line && start_col >= end_col) {
+                        // Invalid range, use a minimal valid range at start position
+                        (start_line, start_col, start_line, start_col + 1)
+                    } else {
+                        (start_line, start_col, end_line, end_col)
+                    };
+                
+                self.log_trace(TraceValue::Messages, format!(
+                    "Diagnostic range: {}:{} to {}:{}",
+                    final_start_line, final_start_col, final_end_line, final_end_col
+                ))
+                .await;
+                
+                vec![Diagnostic {
+                    range: Range {
+                        start: Position {
+                            line: final_start_line,
+                            character: final_start_col,
+                        },
+                        end: Position {
+                            line: final_end_line,
+                            character: final_end_col,
+                        },
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: DiagnosticCode::ParseError.code(),
+                    code_description: DiagnosticCode::ParseError.description(),
+                    source: Some("kanata-lsp".to_string()),
+                    message: display_message,
+                    related_information: (!related.is_empty()).then_some(related),
+                    tags: None,
+                    data: (!expected.is_empty()).then(|| serde_json::json!({ "expected": expected })),
+                }]
+            }
+        };
+        diagnostics.extend(symbol_diagnostics);
+
+        // Store diagnostics in cache for pull diagnostics
+        self.diagnostics_cache.write().await.insert(uri.clone(), diagnostics.clone());
+
+        // Also publish diagnostics for push model
+        if self.config.read().await.diagnostics_mode.pushes() {
+            self.client
+                .publish_diagnostics(uri.clone(), diagnostics, None)
+                .await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let stdin = tokio::io::stdin();
+    let stdout = tokio::io::stdout();
+
+    let (service, socket) = LspService::build(|client| KanataLanguageServer {
+        client,
+        config: Arc::new(RwLock::new(ServerConfig::default())),
+        documents: Arc::new(RwLock::new(HashMap::new())),
+        diagnostics_cache: Arc::new(RwLock::new(HashMap::new())),
+        symbols_cache: Arc::new(RwLock::new(HashMap::new())),
+        defsrc_cache: Arc::new(RwLock::new(HashMap::new())),
+        includes: Arc::new(RwLock::new(HashMap::new())),
+        includers: Arc::new(RwLock::new(HashMap::new())),
+        reference_cache: Arc::new(RwLock::new(HashMap::new())),
+        trace: Arc::new(RwLock::new(TraceValue::default())),
+    })
+    .custom_method("$/setTrace", KanataLanguageServer::set_trace)
+    .finish();
+    
+    Server::new(stdin, stdout, socket).serve(service).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn uri() -> Url {
+        Url::parse("file:///tmp/a.kbd").unwrap()
+    }
+    
+    fn service() -> (LspService<KanataLanguageServer>, tower_lsp::ClientSocket) {
+        LspService::new(|client| KanataLanguageServer {
+            client,
+            config: Arc::new(RwLock::new(ServerConfig::default())),
+            documents: Arc::new(RwLock::new(HashMap::new())),
+            diagnostics_cache: Arc::new(RwLock::new(HashMap::new())),
+            symbols_cache: Arc::new(RwLock::new(HashMap::new())),
+            defsrc_cache: Arc::new(RwLock::new(HashMap::new())),
+            includes: Arc::new(RwLock::new(HashMap::new())),
+            includers: Arc::new(RwLock::new(HashMap::new())),
+            reference_cache: Arc::new(RwLock::new(HashMap::new())),
+            trace: Arc::new(RwLock::new(TraceValue::default())),
+        })
+    }
+    
+    async fn open(server: &KanataLanguageServer, uri: &Url, text: &str) {
+        server
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(uri.clone(), "kanata".to_string(), 1, text.to_string()),
+            })
+            .await;
+    }
+    
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+    }
+    
+    /// `text` with `edits` applied, last edit first so earlier positions stay put.
+    fn apply(text: &str, mut edits: Vec<TextEdit>) -> String {
+        edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+        let mut text = text.to_string();
+        for edit in edits.into_iter().rev() {
+            let start = KanataLanguageServer::position_to_offset(&text, edit.range.start);
+            let end = KanataLanguageServer::position_to_offset(&text, edit.range.end);
+            text.replace_range(start..end, &edit.new_text);
+        }
+        text
+    }
+    
+    fn format(text: &str) -> String {
+        let masked = KanataLanguageServer::mask_comments(text);
+        let layout = KanataLanguageServer::parse_defsrc_layout(&masked, DEFAULT_TAB_SIZE).expect("text has a defsrc");
+        KanataLanguageServer::format_document(text, &layout, &FormatOptions::default())
+    }
+    
+    #[test]
+    fn formatting_fixtures_is_idempotent() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let masked = KanataLanguageServer::mask_comments(&text);
+            let Some(layout) = KanataLanguageServer::parse_defsrc_layout(&masked, DEFAULT_TAB_SIZE) else {
+                continue;
+            };
+            let options = FormatOptions::default();
+            let once = KanataLanguageServer::format_document(&text, &layout, &options);
+            let twice = KanataLanguageServer::format_document(&once, &layout, &options);
+            assert_eq!(once, twice, "{}", path.display());
+            
+            // Only whitespace may change
+            let content = |text: &str| text.split_whitespace().collect::<String>();
+            assert_eq!(content(&text), content(&once), "{}", path.display());
+        }
+    }
+    
+    #[test]
+    fn positions_count_utf16_units() {
+        let text = ";; é\n(x🙂 @a)";
+        let ranges = KanataLanguageServer::alias_reference_ranges(text, "a");
+        assert_eq!(ranges, vec![Range::new(Position::new(1, 5), Position::new(1, 7))]);
+        
+        let offset = text.find("@a").unwrap();
+        assert_eq!(KanataLanguageServer::position_to_offset(text, Position::new(1, 5)), offset);
+        // A column inside the surrogate pair stays on the emoji
+        assert_eq!(KanataLanguageServer::position_to_offset(text, Position::new(1, 3)), text.find('🙂').unwrap());
+        assert_eq!(KanataLanguageServer::position_to_offset(text, Position::new(0, 9)), text.find('\n').unwrap());
+        
+        // Masking keeps the columns of whatever follows a comment or string
+        let text = "(x #| 🙂 |# \"é\" @a)";
+        let masked = KanataLanguageServer::mask_comments_and_strings(text);
+        assert_eq!(masked.len(), text.len());
+        let ranges = KanataLanguageServer::alias_reference_ranges(&masked, "a");
+        assert_eq!(ranges, vec![Range::new(Position::new(0, 16), Position::new(0, 18))]);
+    }
+    
+    #[test]
+    fn alias_body_range_spans_lines() {
+        let text = "(defalias\n  hm (tap-hold 200 200\n        a\n        lmet)\n  x y)";
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), text);
+        let body = symbols.aliases["hm"].body_range.unwrap();
+        let start = KanataLanguageServer::position_to_offset(text, body.start);
+        let end = KanataLanguageServer::position_to_offset(text, body.end);
+        assert_eq!(&text[start..end], "(tap-hold 200 200\n        a\n        lmet)");
+        let body = symbols.aliases["x"].body_range.unwrap();
+        assert_eq!(body, Range::new(Position::new(4, 4), Position::new(4, 5)));
+    }
+    
+    #[test]
+    fn words_on_non_ascii_lines() {
+        let word = |text: &str, character| KanataLanguageServer::get_word_at_position(text, Position::new(0, character));
+        assert_eq!(word("(x 🙂 @nav-é)", 7), "@nav-é");
+        assert_eq!(word("(x 🙂 @nav-é)", 3), "");
+        assert_eq!(word("é ;; ✓", 5), "");
+        assert_eq!(word("é", 9), "");
+        
+        // Every column of a line with wide characters is a valid cursor
+        let text = fixture("unicode.kbd");
+        for (line, content) in text.lines().enumerate() {
+            for character in 0..=content.encode_utf16().count() as u32 {
+                KanataLanguageServer::get_word_at_position(&text, Position::new(line as u32, character));
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn definition_from_cursor_on_at() {
+        let word = |text: &str, character| KanataLanguageServer::get_word_at_position(text, Position::new(0, character));
+        assert_eq!(word("  @foo-bar b", 2), "@foo-bar");
+        assert_eq!(word("(x 🙂 @nav)", 6), "@nav");
+        assert_eq!(word("@x", 0), "@x");
+        
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::parse("file:///nonexistent/kanata-at.kbd").unwrap();
+        open(server, &uri, "(defalias nav x)\n(deflayer base @nav)").await;
+        let def = server.find_definition(&uri, Position::new(1, 15)).await.unwrap();
+        assert_eq!(def.range, Range::new(Position::new(0, 10), Position::new(0, 13)));
+    }
+    
+    #[tokio::test]
+    async fn configuration_changes_merge_onto_startup_options() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let startup = serde_json::json!({ "validation": { "timeoutMs": 500 }, "rename": { "updateComments": true } });
+        *server.config.write().await = ServerConfig::from_options(Some(&startup));
+        
+        for settings in [serde_json::Value::Null, serde_json::json!({}), serde_json::json!({ "kanata-lsp": {} })] {
+            server.did_change_configuration(DidChangeConfigurationParams { settings }).await;
+        }
+        let changed = serde_json::json!({ "formatting": { "columnAlign": "right" } });
+        server.did_change_configuration(DidChangeConfigurationParams { settings: changed }).await;
+        
+        let config = server.config.read().await;
+        assert_eq!(config.validation_timeout, Duration::from_millis(500));
+        assert!(config.rename_update_comments);
+        assert_eq!(config.format.column_align, ColumnAlign::Right);
+    }
+    
+    #[test]
+    fn commented_out_forms_are_not_indexed() {
+        let text = "#| (defalias x a) |#\n(defalias y b)\n;; (deflayer z a)\n(deflayer base a)\n#|\n(deflayer q\n  a)\n|#";
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), text);
+        assert!(!symbols.aliases.contains_key("x"));
+        assert!(symbols.aliases.contains_key("y"));
+        assert!(symbols.layers.contains_key("base"));
+        assert!(!symbols.layers.contains_key("z"));
+        assert!(!symbols.layers.contains_key("q"));
+    }
+    
+    #[tokio::test]
+    async fn layer_references_skip_unrelated_text() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::parse("file:///nonexistent/kanata-layer-refs.kbd").unwrap();
+        let text = "(defsrc base a)\n(deflayer base a b)\n(deflayer other (layer-switch base) base)\n;; (layer-toggle base)\n(defalias x (layer-while-held base))";
+        open(server, &uri, text).await;
+        
+        let references = server
+            .references(ReferenceParams {
+                text_document_position: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri), Position::new(1, 11)),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: ReferenceContext { include_declaration: false },
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let starts: Vec<Position> = references.iter().map(|location| location.range.start).collect();
+        assert_eq!(starts, vec![Position::new(2, 30), Position::new(4, 30)]);
+    }
+    
+    #[test]
+    fn crlf_fixture_ranges_and_formatting() {
+        let text = fixture("crlf.kbd");
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), &text);
+        assert_eq!(symbols.layers["crlf"].range, Range::new(Position::new(6, 10), Position::new(6, 14)));
+        
+        let formatted = format(&text);
+        assert_eq!(formatted, "(defsrc\r\n  a b\r\n  c d\r\n)\r\n\r\n(deflayer crlf\r\n  1 2\r\n  3 4\r\n)\r\n");
+        let end = KanataLanguageServer::offset_to_position(&text, text.len());
+        assert_eq!(end, Position::new(9, 0));
+    }
+    
+    #[test]
+    fn column_align_modes() {
+        let text = "(defsrc aaaa bbbbb)\n(deflayer x a b)";
+        let layout = KanataLanguageServer::parse_defsrc_layout(text, DEFAULT_TAB_SIZE).unwrap();
+        let format = |column_align| {
+            KanataLanguageServer::format_document(text, &layout, &FormatOptions { column_align, ..FormatOptions::default() })
+        };
+        assert!(format(ColumnAlign::Left).ends_with("\n  a    b\n)"));
+        assert!(format(ColumnAlign::Right).ends_with("\n     a     b\n)"));
+        assert!(format(ColumnAlign::Center).ends_with("\n   a     b\n)"));
+    }
+    
+    #[test]
+    fn tab_indented_names_have_exact_ranges() {
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), &fixture("tab-indented.kbd"));
+        assert_eq!(symbols.layers["arrows"].range, Range::new(Position::new(5, 11), Position::new(5, 17)));
+        
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), " \t(defalias \t esc-ctl (tap-hold 200 200 esc lctl))");
+        assert_eq!(symbols.aliases["esc-ctl"].range, Range::new(Position::new(0, 14), Position::new(0, 21)));
+    }
+    
+    #[test]
+    fn blank_lines_between_forms_are_stable() {
+        let text = "(defsrc a b)\n\n\n\n(deflayer x\n\n  1 2)\n(defalias\n  q w\n\n  e r)\n;; c\n\n\n(deflayer y 3 4)\n";
+        let once = format(text);
+        assert_eq!(format(&once), once);
+        assert!(once.starts_with("(defsrc a b)\n\n(deflayer x\n"), "{once}");
+        assert!(once.contains("  q w\n\n  e r)\n;; c\n\n(deflayer y"), "{once}");
+        
+        let layout = KanataLanguageServer::parse_defsrc_layout(text, DEFAULT_TAB_SIZE).unwrap();
+        let options = FormatOptions { blank_lines_between_forms: 2, ..FormatOptions::default() };
+        let once = KanataLanguageServer::format_document(text, &layout, &options);
+        assert_eq!(KanataLanguageServer::format_document(&once, &layout, &options), once);
+        assert!(once.starts_with("(defsrc a b)\n\n\n(deflayer x\n"), "{once}");
+    }
+    
+    #[tokio::test]
+    async fn layers_without_defsrc() {
+        let text = fixture("no-defsrc.kbd");
+        let masked = KanataLanguageServer::mask_comments(&text);
+        assert!(KanataLanguageServer::parse_defsrc_layout(&masked, DEFAULT_TAB_SIZE).is_none());
+        assert!(KanataLanguageServer::sparse_layer_diagnostics(&masked, 2).is_empty());
+        assert!(KanataLanguageServer::layer_length_lenses(&uri(), &masked).is_empty());
+        assert_eq!(KanataLanguageServer::defsrc_item_for(&masked, text.find("a b c").unwrap()), None);
+        
+        let (service, _socket) = service();
+        let server = service.inner();
+        let diagnostics = server.missing_defsrc_diagnostics(&uri(), &masked).await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::INFORMATION));
+        assert_eq!(diagnostics[0].range.start.line, 6);
+    }
+    
+    #[test]
+    fn tab_separated_defsrc_columns() {
+        let text = fixture("tab-indented.kbd");
+        let masked = KanataLanguageServer::mask_comments(&text);
+        let format = |tab_size| {
+            let layout = KanataLanguageServer::parse_defsrc_layout(&masked, tab_size).unwrap();
+            KanataLanguageServer::format_document(&text, &layout, &FormatOptions { tab_size, ..FormatOptions::default() })
+        };
+        
+        // Each column is as wide as the defsrc's tab stops, so the rows line up
+        let formatted = format(8);
+        assert!(formatted.contains("\t(deflayer arrows\n\t  _       left    right\n\t  _       up      down\n\t)"), "{formatted:?}");
+        
+        let text = "(defsrc\n\tesc\ta\tb\n\ttab\tq\tw\n)\n(deflayer x\n  _ lft rght\n  _ up dn\n)";
+        let layout = KanataLanguageServer::parse_defsrc_layout(text, 4).unwrap();
+        assert_eq!(layout, vec![vec![3, 3, 1], vec![3, 3, 1]]);
+        let formatted = KanataLanguageServer::format_document(text, &layout, &FormatOptions { tab_size: 4, ..FormatOptions::default() });
+        assert!(formatted.ends_with("(deflayer x\n  _   lft rght\n  _   up  dn\n)"), "{formatted:?}");
+        
+        assert_eq!(KanataLanguageServer::display_width("\tab\t", 0, 4), 8);
+        assert_eq!(KanataLanguageServer::display_width("\t", 6, 4), 2);
+    }
+    
+    #[tokio::test]
+    async fn shadowed_alias_resolves_to_the_one_kanata_uses() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let uri = Url::from_file_path(dir.join("shadowed-alias.kbd")).unwrap();
+        let included = Url::from_file_path(dir.join("shadowed-alias-included.kbd")).unwrap();
+        let text = fixture("shadowed-alias.kbd");
+        open(server, &uri, &text).await;
+        
+        let at = |needle: &str| KanataLanguageServer::offset_to_position(&text, text.find(needle).unwrap() + 1);
+        let def = server.find_definition(&uri, at("@esc-ctl")).await.unwrap();
+        assert_eq!(def.uri, uri);
+        let def = server.find_definition(&uri, at("@home-a")).await.unwrap();
+        assert_eq!(def.uri, included);
+        
+        // Including the file first makes its definition the one Kanata reads first
+        let reordered = Url::from_file_path(dir.join("shadowed-alias-reordered.kbd")).unwrap();
+        let text = "(include shadowed-alias-included.kbd)\n(defalias esc-ctl esc)\n(defsrc a)\n(deflayer base @esc-ctl)";
+        open(server, &reordered, text).await;
+        let position = KanataLanguageServer::offset_to_position(text, text.find("@esc-ctl").unwrap() + 1);
+        let def = server.find_definition(&reordered, position).await.unwrap();
+        assert_eq!(def.uri, included);
+    }
+    
+    #[test]
+    fn quoted_layer_names() {
+        let text = fixture("quoted-layer.kbd");
+        let masked = KanataLanguageServer::mask_comments(&text);
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), &text);
+        let range = symbols.layers["my layer"].range;
+        let (start, end) = (
+            KanataLanguageServer::position_to_offset(&text, range.start),
+            KanataLanguageServer::position_to_offset(&text, range.end),
+        );
+        assert_eq!(&text[start..end], "my layer");
+        assert_eq!(KanataLanguageServer::layer_name_ranges(&masked, "my layer", true).len(), 2);
+        
+        let text = "(defsrc a b c)\n(deflayer \"my layer\" _    (layer-switch base)   _)\n(deflayer a a  b c)";
+        assert_eq!(
+            format(text),
+            "(defsrc a b c)\n(deflayer \"my layer\"\n  _ (layer-switch base) _\n)\n(deflayer a\n  a b c\n)"
+        );
+    }
+    
+    #[tokio::test]
+    async fn formatting_keeps_one_trailing_newline() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        for (name, text) in [("newline", "(defsrc a b)\n(deflayer x   c d)\n"), ("none", "(defsrc a b)\n(deflayer x   c d)")] {
+            let uri = Url::parse(&format!("file:///nonexistent/kanata-{}.kbd", name)).unwrap();
+            open(server, &uri, text).await;
+            let edits = server
+                .formatting(DocumentFormattingParams {
+                    text_document: TextDocumentIdentifier::new(uri),
+                    options: FormattingOptions { tab_size: 4, insert_spaces: true, ..FormattingOptions::default() },
+                    work_done_progress_params: Default::default(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let formatted = apply(text, edits);
+            assert!(formatted.starts_with("(defsrc a b)\n(deflayer x\n  c d\n)"), "{formatted:?}");
+            assert_eq!(formatted.ends_with(")\n"), text.ends_with('\n'), "{formatted:?}");
+            assert!(!formatted.ends_with("\n\n"), "{formatted:?}");
+        }
+    }
+    
+    #[tokio::test]
+    async fn bare_and_quoted_include_paths() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let included = Url::from_file_path(dir.join("include-layers.kbd")).unwrap();
+        
+        for name in ["include-bare.kbd", "include-quoted.kbd"] {
+            let uri = Url::from_file_path(dir.join(name)).unwrap();
+            let text = fixture(name);
+            open(server, &uri, &text).await;
+            let position = KanataLanguageServer::offset_to_position(&text, text.find("@nav-esc").unwrap() + 1);
+            let def = server.find_definition(&uri, position).await.unwrap();
+            assert_eq!(def.uri, included, "{name}");
+        }
+        
+        let uri = Url::from_file_path(dir.join("include-missing.kbd")).unwrap();
+        for text in ["(include missing.kbd)", "(include \"missing.kbd\")"] {
+            let diagnostics = server.index_includes(&uri, text).await;
+            assert_eq!(diagnostics.len(), 1, "{text}");
+            assert_eq!(diagnostics[0].code, DiagnosticCode::MissingInclude.code());
+            assert_eq!(diagnostics[0].range.start.line, 0);
+        }
+    }
+    
+    #[tokio::test]
+    async fn rename_with_repeated_references_on_a_line() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::parse("file:///nonexistent/kanata-rename.kbd").unwrap();
+        let text = "(defalias a x)\n(deflayer base @a @a @a)";
+        open(server, &uri, text).await;
+        
+        let edit = server
+            .rename(RenameParams {
+                text_document_position: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri.clone()), Position::new(1, 19)),
+                new_name: "b".to_string(),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let edits = edit.changes.unwrap().remove(&uri).unwrap();
+        assert_eq!(edits.len(), 4);
+        for pair in edits.windows(2) {
+            assert!(pair[0].range.end <= pair[1].range.start, "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+        assert_eq!(apply(text, edits), "(defalias b x)\n(deflayer base @b @b @b)");
+    }
+    
+    #[tokio::test]
+    async fn untitled_documents() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        let text = "(defalias a b)\n(deflayer base @a)";
+        open(server, &uri, text).await;
+        
+        let def = server.find_definition(&uri, Position::new(1, 16)).await.unwrap();
+        assert_eq!(def.uri, uri);
+        assert_eq!(def.range, Range::new(Position::new(0, 10), Position::new(0, 11)));
+        
+        let hover = server
+            .hover(HoverParams {
+                text_document_position_params: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri), Position::new(1, 16)),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert!(hover.is_some());
+    }
+    
+    #[test]
+    fn range_formatting_edits_only_selected_layers() {
+        let text = "(defsrc a   b)\n;; é  keep   me\n(deflayer one   1 2)\n(defalias  x  y)\n(deflayer two   🙂 é)\n(deflayer three   5 6)\n";
+        let masked = KanataLanguageServer::mask_comments(text);
+        let layout = KanataLanguageServer::parse_defsrc_layout(&masked, DEFAULT_TAB_SIZE).unwrap();
+        let selection = Range::new(Position::new(2, 0), Position::new(4, 3));
+        let edits = KanataLanguageServer::range_format_edits(text, &layout, &FormatOptions::default(), selection);
+        
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].range, Range::new(Position::new(4, 0), Position::new(4, 21)));
+        assert_eq!(
+            apply(text, edits),
+            "(defsrc a   b)\n;; é  keep   me\n(deflayer one\n  1 2\n)\n(defalias  x  y)\n(deflayer two\n  🙂 é\n)\n(deflayer three   5 6)\n"
+        );
+    }
+    
+    #[tokio::test]
+    async fn numeric_layer_arguments_are_layer_names() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::from_file_path(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/numeric-layers.kbd")).unwrap();
+        let text = fixture("numeric-layers.kbd");
+        open(server, &uri, &text).await;
+        
+        let transitions = server.layer_transitions().await;
+        assert_eq!(transitions.len(), 3);
+        assert!(transitions.iter().all(|transition| transition.defined), "{transitions:?}");
+        
+        let masked = KanataLanguageServer::mask_comments(&text);
+        assert!(server.unused_symbol_diagnostics(&uri, &masked).await.is_empty());
+    }
+    
+    #[test]
+    fn layers_before_defsrc_are_formatted() {
+        let formatted = format(&fixture("defsrc-last.kbd"));
+        assert!(formatted.contains("(deflayer base\n  @caps a s d\n  lsft z x c\n)"), "{formatted}");
+        assert!(formatted.contains("(deflayer nav\n  _    left down right\n  _    _ _ _\n)"), "{formatted}");
+    }
+    
+    #[tokio::test]
+    async fn aliases_inside_alias_bodies() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::parse("file:///nonexistent/kanata-nested-aliases.kbd").unwrap();
+        let text = fixture("nested-aliases.kbd");
+        open(server, &uri, &text).await;
+        let position = |needle: &str, skip: usize| KanataLanguageServer::offset_to_position(&text, text.find(needle).unwrap() + skip);
+        
+        // From a use inside another alias's body back to the definition
+        let def = server.find_definition(&uri, position("(multi @a", 8)).await.unwrap();
+        assert_eq!(def.range.start, position("a (tap-hold", 0));
+        
+        let edit = server
+            .rename(RenameParams {
+                text_document_position: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri.clone()), position("a (tap-hold", 0)),
+                new_name: "z".to_string(),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let renamed = apply(&text, edit.changes.unwrap().remove(&uri).unwrap());
+        assert!(renamed.contains("  z (tap-hold 200 200 a lctl)\n  ab (multi @z lsft)\n  b (macro @z @ab)\n"), "{renamed}");
+        assert!(renamed.contains("  @b @z @ab\n"), "{renamed}");
+    }
+    
+    #[test]
+    fn completion_context_through_nested_actions() {
+        use CompletionContext::*;
+        let context = |text: &str| KanataLanguageServer::completion_context(text, text.len());
+        assert_eq!(context("(defalias x (multi @a (layer-toggle "), Some(Layer));
+        assert_eq!(context("(deflayer b (multi (fork a (layer-switch na"), Some(Layer));
+        assert_eq!(context("(defalias x (multi (tap-hold 1 1 @"), Some(Alias));
+        assert_eq!(context("(defalias x (multi (tap-dance 200 (a (layer-switch "), Some(Layer));
+        assert_eq!(context("(deflayer b (multi (fork a b (sp"), Some(Keycode));
+        assert_eq!(context("(defalias x (multi (ta"), Some(Action));
+    }
+    
+    #[tokio::test]
+    async fn references_see_unsaved_edits() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        // Not on disk, so only the edited text can answer
+        let uri = Url::parse("file:///nonexistent/kanata-refs.kbd").unwrap();
+        open(server, &uri, "(defalias a x)\n(deflayer base @a)").await;
+        server
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "(defalias a x)\n(deflayer base @a @a)\n(deflayer two @a)".to_string(),
+                }],
+            })
+            .await;
+        
+        let references = server
+            .references(ReferenceParams {
+                text_document_position: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri), Position::new(0, 10)),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: ReferenceContext { include_declaration: false },
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let starts: Vec<Position> = references.iter().map(|location| location.range.start).collect();
+        assert_eq!(starts, vec![Position::new(1, 15), Position::new(1, 18), Position::new(2, 14)]);
+    }
+    
+    #[test]
+    fn strings_with_parens_are_single_items() {
+        let text = "(defsrc a b)\n(deflayer base\n  (cmd \"echo\" \"a ) b\")     b\n)\n(deflayer two\n  a    (unicode r#\")\"#)\n)\n";
+        assert_eq!(
+            format(text),
+            "(defsrc a b)\n(deflayer base\n  (cmd \"echo\" \"a ) b\") b\n)\n(deflayer two\n  a (unicode r#\")\"#)\n)\n"
+        );
+        
+        let symbols = KanataLanguageServer::extract_symbols(&uri(), &fixture("cmd-strings.kbd"));
+        assert!(symbols.aliases.contains_key("lp"));
+        assert!(symbols.layers.contains_key("base"));
+    }
+    
+    #[test]
+    fn include_paths_resolve_across_directories() {
+        let dir = std::env::temp_dir().join(format!("kanata-lsp-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("a/common.kbd"), "").unwrap();
+        let resolved = KanataLanguageServer::resolve_include(Some(&dir.join("a/b/main.kbd")), "../common.kbd", None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolved, Some(dir.join("a/common.kbd")));
+        
+        // A drive-letter URI round-trips, and relative includes join onto it
+        let uri = Url::parse("file:///C:/Users/me/kanata/main.kbd").unwrap();
+        let path = uri.to_file_path().unwrap();
+        assert_eq!(Url::from_file_path(&path).unwrap(), uri);
+        let include = KanataLanguageServer::normalize_path(&path.parent().unwrap().join("../shared/layers.kbd"));
+        assert_eq!(Url::from_file_path(include).unwrap().as_str(), "file:///C:/Users/me/shared/layers.kbd");
+    }
+    
+    #[cfg(windows)]
+    #[test]
+    fn windows_drive_paths() {
+        let path = Url::parse("file:///C:/Users/me/kanata/main.kbd").unwrap().to_file_path().unwrap();
+        assert_eq!(path, PathBuf::from(r"C:\Users\me\kanata\main.kbd"));
+        let include = KanataLanguageServer::normalize_path(&path.parent().unwrap().join(r"..\x.kbd"));
+        assert_eq!(include, PathBuf::from(r"C:\Users\me\x.kbd"));
+    }
+    
+    #[test]
+    fn validations_get_their_own_temp_files() {
+        let uri = Url::parse("file:///a/my%20cfg.kbd").unwrap();
+        let (first, second) = (KanataLanguageServer::temp_file_path(&uri), KanataLanguageServer::temp_file_path(&uri));
+        assert_ne!(first, second);
+        assert_ne!(first.parent(), second.parent());
+        assert_eq!(first.file_name().unwrap(), "my cfg.kbd");
+        
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        assert_ne!(KanataLanguageServer::temp_file_path(&untitled), KanataLanguageServer::temp_file_path(&untitled));
+    }
+    
+    #[test]
+    fn tap_hold_toggle_ends_at_utf16_column() {
+        let text = "(defsrc a b)\n(deflayer base a é)\n";
+        let layout = KanataLanguageServer::parse_defsrc_layout(&KanataLanguageServer::mask_comments(text), DEFAULT_TAB_SIZE);
+        let (_, edit) = KanataLanguageServer::tap_hold_toggle_edit(text, layout.as_deref(), &FormatOptions::default(), text.rfind("a é").unwrap()).unwrap();
+        assert_eq!(edit.range.end, Position::new(1, 19));
+        assert_eq!(apply(text, vec![edit]), "(defsrc a b)\n(deflayer base\n  (tap-hold 200 200 a @layer) é\n)\n");
+    }
+    
+    #[tokio::test]
+    async fn unused_symbols_use_indexed_references() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let text = "(defsrc a)\n(deflayer base a)\n(defalias x a y b)\n";
+        open(server, &uri(), text).await;
+        
+        // Only the index knows this document; its text can't be read back
+        let other = Url::parse("file:///tmp/kanata-lsp-missing/b.kbd").unwrap();
+        server.index_document(&other, "(deflayer other @x)").await;
+        
+        let masked = KanataLanguageServer::mask_comments(text);
+        let unused: Vec<String> = server.unused_symbol_diagnostics(&uri(), &masked).await
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(unused, vec!["Alias `y` is never used"]);
+    }
+    
+    #[test]
+    fn defcfg_values_and_unknown_options() {
+        let masked = KanataLanguageServer::mask_comments(concat!(
+            "(defcfg\n",
+            "  process-unmapped-keys (all-except f1 f2)\n",
+            "  linux-dev \"/dev/input/by-id/kbd\"\n",
+            "  windows-sync-keystates yes\n",
+            "  tooltip-no-base yes\n",
+            "  danger-enable-cmd maybe\n",
+            "  made-up-option yes\n",
+            ")\n",
+        ));
+        let diagnostics: Vec<(Option<DiagnosticSeverity>, String)> = KanataLanguageServer::defcfg_diagnostics(&masked)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.message))
+            .collect();
+        assert_eq!(diagnostics, vec![
+            (Some(DiagnosticSeverity::WARNING), "`danger-enable-cmd` expects `yes` or `no`".to_string()),
+            (Some(DiagnosticSeverity::HINT), "Unknown defcfg option `made-up-option`".to_string()),
+        ]);
+    }
+    
+    #[tokio::test]
+    async fn virtual_keys_defined_in_includes() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::from_file_path(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/include-definitions.kbd")).unwrap();
+        let text = fixture("include-definitions.kbd");
+        open(server, &uri, &text).await;
+        
+        let masked = KanataLanguageServer::mask_comments(&text);
+        let undefined: Vec<String> = server.undefined_sequence_diagnostics(&uri, &masked).await
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(undefined, vec!["Sequence or virtual key `vk-missing` is not defined"]);
+    }
+    
+    #[tokio::test]
+    async fn templates_defined_in_includes() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        let uri = Url::from_file_path(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/include-definitions.kbd")).unwrap();
+        let text = fixture("include-definitions.kbd");
+        open(server, &uri, &text).await;
+        
+        let masked = KanataLanguageServer::mask_comments(&text);
+        let diagnostics: Vec<(Option<NumberOrString>, String)> = server.template_call_diagnostics(&uri, &masked).await
+            .into_iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.message))
+            .collect();
+        assert_eq!(diagnostics, vec![
+            (DiagnosticCode::TemplateArity.code(), "Template `hold` expects 2 argument(s) but got 1".to_string()),
+            (DiagnosticCode::UndefinedTemplate.code(), "Template `nowhere` is not defined".to_string()),
+        ]);
+    }
+    
+    #[tokio::test]
+    async fn configuration_changes_keep_the_diagnostics_trigger() {
+        let (service, _socket) = service();
+        let server = service.inner();
+        server.config.write().await.diagnostics_trigger = DiagnosticsTrigger::Open;
+        open(server, &uri(), "(defsrc a)\n(deflayer base a)\n").await;
+        server.diagnostics_cache.write().await.clear();
+        
+        server.did_change_configuration(DidChangeConfigurationParams {
+            settings: serde_json::json!({ "validation": { "timeoutMs": 1000 } }),
+        }).await;
+        assert!(server.diagnostics_cache.read().await.is_empty());
+        
+        server.did_change_configuration(DidChangeConfigurationParams {
+            settings: serde_json::json!({ "diagnostics": { "trigger": "onType" } }),
+        }).await;
+        assert!(server.diagnostics_cache.read().await.contains_key(&uri()));
+    }
 }
@@ -1,8 +1,86 @@
-use zed_extension_api::{self as zed, LanguageServerId, Result};
+use zed_extension_api::{self as zed, settings::LspSettings, LanguageServerId, Result};
+use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
+
+const DEFAULT_REPOSITORY: &str = "willpuckett/zed-kanata";
+const DEFAULT_ASSET_TEMPLATE: &str = "kanata-lsp-{os}-{arch}{ext}";
+
+/// Where the language server binary is downloaded from, configurable for forks under
+/// `lsp.kanata-lsp.settings.download`.
+struct DownloadSettings {
+    /// GitHub `owner/repo` slug whose releases carry the binaries
+    repository: String,
+    /// Release asset name, with `{os}`, `{arch}` and optionally `{ext}` placeholders
+    asset_template: String,
+    /// Whether pre-releases count as the latest release
+    prerelease: bool,
+}
+
+impl DownloadSettings {
+    fn for_worktree(worktree: &zed::Worktree) -> Result<Self> {
+        let download = LspSettings::for_worktree("kanata-lsp", worktree)
+            .ok()
+            .and_then(|settings| settings.settings)
+            .and_then(|settings| settings.get("download").cloned());
+        let setting = |key: &str| {
+            download
+                .as_ref()
+                .and_then(|download| download.get(key))
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+        };
+
+        let repository = setting("repository").unwrap_or_else(|| DEFAULT_REPOSITORY.to_string());
+        let asset_template = setting("assetName").unwrap_or_else(|| DEFAULT_ASSET_TEMPLATE.to_string());
+        if !asset_template.contains("{os}") || !asset_template.contains("{arch}") {
+            return Err(format!(
+                "download.assetName {asset_template:?} must contain the {{os}} and {{arch}} placeholders"
+            ));
+        }
+
+        // Not `binary.prerelease`: Zed hands extensions only the path, arguments and env
+        // of the binary settings, so any other key there never arrives
+        let prerelease = download
+            .as_ref()
+            .and_then(|download| download.get("prerelease"))
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false);
+
+        Ok(Self {
+            repository,
+            asset_template,
+            prerelease,
+        })
+    }
+
+    /// The release asset to download for a platform, from the asset name template.
+    fn asset_name(&self, platform: zed::Os, arch: zed::Architecture) -> String {
+        let os = match platform {
+            zed::Os::Mac => "macos",
+            zed::Os::Linux => "linux",
+            zed::Os::Windows => "windows",
+        };
+        let arch = match arch {
+            zed::Architecture::Aarch64 => "aarch64",
+            zed::Architecture::X8664 => "x86_64",
+            zed::Architecture::X86 => "x86",
+        };
+        self.asset_template
+            .replace("{os}", os)
+            .replace("{arch}", arch)
+            .replace("{ext}", executable_extension(platform))
+    }
+}
+
+fn executable_extension(platform: zed::Os) -> &'static str {
+    if platform == zed::Os::Windows { ".exe" } else { "" }
+}
 
 struct KanataExtension {
     cached_binary_path: Option<String>,
+    /// Shell environment per worktree, fetched once since spawning the shell is slow
+    cached_shell_env: HashMap<u64, zed::EnvVars>,
 }
 
 impl KanataExtension {
@@ -17,43 +95,61 @@ impl KanataExtension {
         }
 
         // Check if we have a cached binary from a previous installation
-        if let Some(path) = &self.cached_binary_path {
-            if fs::metadata(path).map(|stat| stat.is_file()).unwrap_or(false) {
+        if let Some(path) = &self.cached_binary_path
+            && fs::metadata(path).map(|stat| stat.is_file()).unwrap_or(false)
+        {
+            return Ok(path.clone());
+        }
+
+        // Download pre-built binary from GitHub releases, reporting failures in the status
+        // rather than leaving the last "downloading" state up. The platform and asset name
+        // are only worked out here, once there is no usable binary.
+        let installed = DownloadSettings::for_worktree(worktree)
+            .and_then(|download| self.install_latest_binary(language_server_id, &download));
+        let full_path = match (installed, &self.cached_binary_path) {
+            (Ok(path), _) => path,
+            // The cached binary may only have been unreadable for a moment; if it is back,
+            // keep using it rather than failing because the download failed
+            (Err(_), Some(path)) if fs::metadata(path).map(|stat| stat.is_file()).unwrap_or(false) => {
+                zed::set_language_server_installation_status(
+                    language_server_id,
+                    &zed::LanguageServerInstallationStatus::None,
+                );
                 return Ok(path.clone());
             }
-        }
+            (Err(e), _) => {
+                zed::set_language_server_installation_status(
+                    language_server_id,
+                    &zed::LanguageServerInstallationStatus::Failed(e.clone()),
+                );
+                return Err(e);
+            }
+        };
 
-        // Download pre-built binary from GitHub releases
+        self.cached_binary_path = Some(full_path.clone());
+        Ok(full_path)
+    }
+
+    fn install_latest_binary(
+        &self,
+        language_server_id: &LanguageServerId,
+        download: &DownloadSettings,
+    ) -> Result<String> {
         zed::set_language_server_installation_status(
             language_server_id,
             &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
 
         let release = zed::latest_github_release(
-            "willpuckett/zed-kanata",
+            &download.repository,
             zed::GithubReleaseOptions {
                 require_assets: true,
-                pre_release: false,
+                pre_release: download.prerelease,
             },
         )?;
 
         let (platform, arch) = zed::current_platform();
-        
-        // Construct the asset name based on platform and architecture
-        let asset_name = format!(
-            "kanata-lsp-{os}-{arch}{ext}",
-            os = match platform {
-                zed::Os::Mac => "macos",
-                zed::Os::Linux => "linux",
-                zed::Os::Windows => "windows",
-            },
-            arch = match arch {
-                zed::Architecture::Aarch64 => "aarch64",
-                zed::Architecture::X8664 => "x86_64",
-                zed::Architecture::X86 => "x86",
-            },
-            ext = if platform == zed::Os::Windows { ".exe" } else { "" }
-        );
+        let asset_name = download.asset_name(platform, arch);
 
         let asset = release
             .assets
@@ -62,9 +158,7 @@ impl KanataExtension {
             .ok_or_else(|| format!("no asset found matching {asset_name:?}"))?;
 
         let version_dir = format!("kanata-lsp-{}", release.version);
-        let binary_name = format!("kanata-lsp{}", 
-            if platform == zed::Os::Windows { ".exe" } else { "" }
-        );
+        let binary_name = format!("kanata-lsp{}", executable_extension(platform));
         let binary_path = format!("{version_dir}/{binary_name}");
 
         if !fs::metadata(&binary_path).map(|stat| stat.is_file()).unwrap_or(false) {
@@ -83,21 +177,14 @@ impl KanataExtension {
                 &binary_path,
                 zed::DownloadedFileType::Uncompressed,
             )
-            .map_err(|e| format!("failed to download file: {e}"))?;
+            .map_err(|e| format!("failed to download {asset_name}: {e}"))?;
 
             zed::make_file_executable(&binary_path)?;
 
-            // Clean up old versions
-            let entries = fs::read_dir(".")
-                .map_err(|e| format!("failed to list working directory: {e}"))?;
-            for entry in entries {
-                let entry = entry.map_err(|e| format!("failed to load directory entry: {e}"))?;
-                if entry.file_name().to_str() != Some(&version_dir)
-                    && entry.file_name().to_string_lossy().starts_with("kanata-lsp-")
-                {
-                    fs::remove_dir_all(entry.path()).ok();
-                }
-            }
+            // Clean up old versions, still under the downloading status
+            let work_dir = std::env::current_dir()
+                .map_err(|e| format!("failed to get current directory: {e}"))?;
+            Self::remove_old_versions(&work_dir, &version_dir, &binary_name);
         }
 
         zed::set_language_server_installation_status(
@@ -111,15 +198,68 @@ impl KanataExtension {
             .to_string_lossy()
             .to_string();
 
-        self.cached_binary_path = Some(full_path.clone());
         Ok(full_path)
     }
+
+    /// Delete previously downloaded `kanata-lsp-<version>` directories from the extension's
+    /// work directory, keeping `current_version_dir`.
+    ///
+    /// Only directories that contain a `kanata-lsp` binary are removed, so nothing the
+    /// extension didn't create itself is touched. Failures are ignored; whatever is left
+    /// behind is tried again after the next download.
+    fn remove_old_versions(work_dir: &Path, current_version_dir: &str, binary_name: &str) {
+        let Ok(entries) = fs::read_dir(work_dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let path = entry.path();
+            if name == current_version_dir
+                || !name.starts_with("kanata-lsp-")
+                || !path.is_dir()
+                || !path.join(binary_name).is_file()
+            {
+                continue;
+            }
+
+            let _ = fs::remove_dir_all(&path);
+        }
+    }
+
+    /// The worktree's shell environment, extended or overridden by `lsp.kanata-lsp.binary.env`.
+    ///
+    /// The shell's `PATH` is what lets `worktree.which("kanata-lsp")` find a locally
+    /// installed server, so it is kept unless the settings replace it.
+    fn language_server_env(&mut self, worktree: &zed::Worktree) -> zed::EnvVars {
+        let mut env = self
+            .cached_shell_env
+            .entry(worktree.id())
+            .or_insert_with(|| worktree.shell_env())
+            .clone();
+
+        let overrides = LspSettings::for_worktree("kanata-lsp", worktree)
+            .ok()
+            .and_then(|settings| settings.binary)
+            .and_then(|binary| binary.env)
+            .unwrap_or_default();
+        for (key, value) in overrides {
+            match env.iter_mut().find(|(existing, _)| *existing == key) {
+                Some(entry) => entry.1 = value,
+                None => env.push((key, value)),
+            }
+        }
+
+        env
+    }
 }
 
 impl zed::Extension for KanataExtension {
     fn new() -> Self {
         Self {
             cached_binary_path: None,
+            cached_shell_env: HashMap::new(),
         }
     }
 
@@ -133,9 +273,44 @@ impl zed::Extension for KanataExtension {
         Ok(zed::Command {
             command,
             args: vec![],
-            env: worktree.shell_env(),
+            env: self.language_server_env(worktree),
         })
     }
 }
 
 zed_extension_api::register_extension!(KanataExtension);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(asset_template: &str) -> DownloadSettings {
+        DownloadSettings {
+            repository: DEFAULT_REPOSITORY.to_string(),
+            asset_template: asset_template.to_string(),
+            prerelease: false,
+        }
+    }
+
+    #[test]
+    fn asset_names() {
+        let default = download(DEFAULT_ASSET_TEMPLATE);
+        for (platform, arch, expected) in [
+            (zed::Os::Mac, zed::Architecture::Aarch64, "kanata-lsp-macos-aarch64"),
+            (zed::Os::Mac, zed::Architecture::X8664, "kanata-lsp-macos-x86_64"),
+            (zed::Os::Mac, zed::Architecture::X86, "kanata-lsp-macos-x86"),
+            (zed::Os::Linux, zed::Architecture::Aarch64, "kanata-lsp-linux-aarch64"),
+            (zed::Os::Linux, zed::Architecture::X8664, "kanata-lsp-linux-x86_64"),
+            (zed::Os::Linux, zed::Architecture::X86, "kanata-lsp-linux-x86"),
+            (zed::Os::Windows, zed::Architecture::Aarch64, "kanata-lsp-windows-aarch64.exe"),
+            (zed::Os::Windows, zed::Architecture::X8664, "kanata-lsp-windows-x86_64.exe"),
+            (zed::Os::Windows, zed::Architecture::X86, "kanata-lsp-windows-x86.exe"),
+        ] {
+            assert_eq!(default.asset_name(platform, arch), expected);
+        }
+
+        let custom = download("lsp_{arch}_{os}.bin");
+        assert_eq!(custom.asset_name(zed::Os::Windows, zed::Architecture::X8664), "lsp_x86_64_windows.bin");
+        assert_eq!(custom.asset_name(zed::Os::Mac, zed::Architecture::Aarch64), "lsp_aarch64_macos.bin");
+    }
+}